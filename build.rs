@@ -1,3 +1,7 @@
 fn main() {
-    prost_build::compile_protos(&["proto/store_dictionary.proto"], &["proto/"]).unwrap();
+    prost_build::compile_protos(
+        &["proto/store_dictionary.proto", "proto/bundle.proto"],
+        &["proto/"],
+    )
+    .unwrap();
 }
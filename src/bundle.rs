@@ -0,0 +1,416 @@
+use bitar::{clone::CloneOutput, ChunkIndex, HashSum};
+use blake2::{Blake2b, Digest};
+use log::*;
+use prost::Message;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::clone::CloneError;
+use crate::size_str::size_str;
+use crate::store::{
+    check_security_version, parse_dictionary, read_dictionary, store_root_of,
+    write_dictionary_file, ChunkStore,
+};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+use crate::{bundledict, storedict, BUNDLE_MAGIC};
+
+// Bundle file layout: magic, header size (u64 le), header, Blake2b of all
+// preceding bytes, then the data of each chunk in header order.
+fn build_bundle_header(bundle: &bundledict::Bundle) -> Vec<u8> {
+    let mut header: Vec<u8> = vec![];
+    let mut bundle_buf: Vec<u8> = Vec::new();
+    bundle.encode(&mut bundle_buf).expect("encode bundle");
+    header.extend(BUNDLE_MAGIC);
+    header.extend(&(bundle_buf.len() as u64).to_le_bytes());
+    header.extend(bundle_buf);
+    let mut hasher = Blake2b::new();
+    hasher.update(&header);
+    header.extend(&hasher.finalize());
+    header
+}
+
+// Largest bundle header accepted. The header holds the dictionaries and the
+// list of chunks, which is far less even for big releases.
+const MAX_HEADER_SIZE: u64 = 64 * 1024 * 1024;
+
+async fn read_bundle_header(
+    reader: &mut (impl AsyncReadExt + Unpin),
+) -> Result<bundledict::Bundle, CloneError> {
+    let invalid = |reason: &str| CloneError::InvalidBundle(reason.to_string());
+    let mut pre_header = vec![0; BUNDLE_MAGIC.len() + std::mem::size_of::<u64>()];
+    reader
+        .read_exact(&mut pre_header)
+        .await
+        .map_err(|_| invalid("not an ihop bundle"))?;
+    if &pre_header[..BUNDLE_MAGIC.len()] != BUNDLE_MAGIC {
+        return Err(invalid("not an ihop bundle"));
+    }
+    let header_size = u64::from_le_bytes(pre_header[BUNDLE_MAGIC.len()..].try_into().unwrap());
+    if header_size > MAX_HEADER_SIZE {
+        return Err(invalid("header is too large"));
+    }
+    let header_size = header_size as usize;
+    let mut header = vec![0; header_size + 64];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| invalid("header is truncated"))?;
+    let mut hasher = Blake2b::new();
+    hasher.update(&pre_header);
+    hasher.update(&header[..header_size]);
+    if hasher.finalize()[..] != header[header_size..] {
+        return Err(invalid("header checksum mismatch"));
+    }
+    bundledict::Bundle::decode(&header[..header_size])
+        .map_err(|_| invalid("failed to decode header"))
+}
+
+fn dictionary_name(dictionary_path: &Path) -> String {
+    dictionary_path
+        .file_name()
+        .unwrap_or_else(|| panic!("invalid dictionary path {}", dictionary_path.display()))
+        .to_string_lossy()
+        .to_string()
+}
+
+// Pack dictionaries and the chunks they reference into a single file.
+// Chunks shared between dictionaries are only included once and zero
+// chunks not at all.
+pub async fn create(
+    dictionary_paths: &[PathBuf],
+    output: &Path,
+    force_create: bool,
+    lock_wait: LockWait,
+) -> Result<(), CloneError> {
+    create_bundle(None, dictionary_paths, output, force_create, lock_wait).await
+}
+
+// Pack a dictionary and only the chunks not already in the base dictionary.
+pub async fn delta(
+    base_path: &Path,
+    dictionary_path: &Path,
+    output: &Path,
+    force_create: bool,
+    lock_wait: LockWait,
+) -> Result<(), CloneError> {
+    create_bundle(
+        Some(base_path),
+        &[dictionary_path.to_path_buf()],
+        output,
+        force_create,
+        lock_wait,
+    )
+    .await
+}
+
+async fn create_bundle(
+    base_path: Option<&Path>,
+    dictionary_paths: &[PathBuf],
+    output: &Path,
+    force_create: bool,
+    lock_wait: LockWait,
+) -> Result<(), CloneError> {
+    let mut locks = Vec::new();
+    let mut store_roots: Vec<PathBuf> = Vec::new();
+    for dictionary_path in dictionary_paths
+        .iter()
+        .map(|p| p.as_path())
+        .chain(base_path)
+    {
+        let store_root = store_root_of(dictionary_path);
+        if !store_roots.contains(&store_root) {
+            locks.push(
+                StoreLock::acquire(&store_root, LockMode::Shared, lock_wait)
+                    .await
+                    .ok_or_else(|| CloneError::StoreLocked(store_root.clone()))?,
+            );
+            store_roots.push(store_root);
+        }
+    }
+
+    // Collect the chunks to include, together with the store to read them from
+    let mut bundle = bundledict::Bundle {
+        application_version: crate::PKG_VERSION.to_string(),
+        dictionaries: Vec::new(),
+        chunks: Vec::new(),
+        base: None,
+    };
+    let mut chunk_stores: Vec<ChunkStore> = Vec::new();
+    let mut included: HashSet<Vec<u8>> = HashSet::new();
+    if let Some(base_path) = base_path {
+        // Chunks of the base are treated as already included, except for
+        // zero chunks which the base has no file of
+        let base = read_dictionary(base_path).await;
+        let base_store = ChunkStore::for_dictionary(&store_root_of(base_path), &base);
+        included.extend(
+            base.chunk_descriptors
+                .into_iter()
+                .filter(|cd| {
+                    !base_store.is_zero_chunk(
+                        &HashSum::from_slice(&cd.checksum[..]),
+                        cd.source_size as usize,
+                    )
+                })
+                .map(|cd| cd.checksum),
+        );
+        bundle.base = Some(bundledict::BundleBase {
+            name: dictionary_name(base_path),
+            source_checksum: base.source_checksum,
+        });
+    }
+    for dictionary_path in dictionary_paths {
+        let name = dictionary_name(dictionary_path);
+        if bundle.dictionaries.iter().any(|d| d.name == name) {
+            panic!("multiple dictionaries named {}", name);
+        }
+        let dictionary_buf = tokio::fs::read(dictionary_path)
+            .await
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", dictionary_path.display(), err));
+        let dictionary = parse_dictionary(&dictionary_buf)
+            .unwrap_or_else(|err| panic!("{}: {}", dictionary_path.display(), err));
+        let store = ChunkStore::for_dictionary(&store_root_of(dictionary_path), &dictionary);
+        for cd in &dictionary.chunk_descriptors {
+            let hash = HashSum::from_slice(&cd.checksum[..]);
+            if store.is_zero_chunk(&hash, cd.source_size as usize)
+                || !included.insert(cd.checksum.clone())
+            {
+                continue;
+            }
+            bundle.chunks.push(bundledict::BundleChunk {
+                checksum: cd.checksum.clone(),
+                size: cd.source_size,
+            });
+            chunk_stores.push(store.clone());
+        }
+        bundle.dictionaries.push(bundledict::BundleDictionary {
+            name,
+            dictionary: dictionary_buf,
+        });
+    }
+    write_bundle(&bundle, &chunk_stores, output, force_create).await;
+    Ok(())
+}
+
+async fn write_bundle(
+    bundle: &bundledict::Bundle,
+    chunk_stores: &[ChunkStore],
+    output: &Path,
+    force_create: bool,
+) {
+    let output_file = OpenOptions::new()
+        .write(true)
+        .create(force_create)
+        .truncate(force_create)
+        .create_new(!force_create)
+        .open(output)
+        .await
+        .unwrap_or_else(|err| panic!("failed to open {}: {}", output.display(), err));
+    let mut output_file = BufWriter::new(output_file);
+    let header = build_bundle_header(bundle);
+    output_file
+        .write_all(&header)
+        .await
+        .expect("write bundle header");
+    let mut data_size: u64 = 0;
+    for (chunk, store) in bundle.chunks.iter().zip(chunk_stores) {
+        let hash = HashSum::from_slice(&chunk.checksum[..]);
+        let data = store
+            .read_chunk(&hash, chunk.size as usize)
+            .await
+            .unwrap_or_else(|err| panic!("failed to read chunk {}: {}", hash, err));
+        output_file.write_all(&data).await.expect("write chunk");
+        data_size += data.len() as u64;
+    }
+    output_file.flush().await.expect("flush bundle");
+    info!(
+        "Successfully created bundle {} with {} dictionaries and {} chunks ({})",
+        output.display(),
+        bundle.dictionaries.len(),
+        bundle.chunks.len(),
+        size_str(header.len() as u64 + data_size)
+    );
+}
+
+// Check that the base of a delta bundle is in store with all its chunks.
+async fn check_base(
+    store_root: &Path,
+    base: &bundledict::BundleBase,
+    verify_present: bool,
+) -> Result<(), CloneError> {
+    let base_path = store_root.join(&base.name);
+    let base_buf = tokio::fs::read(&base_path)
+        .await
+        .map_err(|_| CloneError::IncompleteBase(format!("{} not found", base_path.display())))?;
+    let dictionary = parse_dictionary(&base_buf)
+        .map_err(|err| CloneError::IncompleteBase(format!("{}: {}", base_path.display(), err)))?;
+    if dictionary.source_checksum != base.source_checksum {
+        return Err(CloneError::IncompleteBase(format!(
+            "{} is not the base of the bundle",
+            base_path.display()
+        )));
+    }
+    let store = ChunkStore::for_dictionary(store_root, &dictionary);
+    let mut missing = 0;
+    for cd in &dictionary.chunk_descriptors {
+        let hash = HashSum::from_slice(&cd.checksum[..]);
+        if !store
+            .chunk_present(verify_present, &hash, cd.source_size as usize)
+            .await
+        {
+            missing += 1;
+        }
+    }
+    if missing > 0 {
+        return Err(CloneError::IncompleteBase(format!(
+            "{} chunks of {} are missing",
+            missing,
+            base_path.display()
+        )));
+    }
+    info!("base {} is complete", base_path.display());
+    Ok(())
+}
+
+// Install the dictionaries of a bundle in a store. Every chunk of the bundle
+// is verified, while only the chunks missing in store are written.
+pub async fn apply(
+    bundle_path: &Path,
+    store_root: &Path,
+    force_create: bool,
+    verify_present: bool,
+    allow_downgrade: bool,
+    lock_wait: LockWait,
+) -> Result<(), CloneError> {
+    let _lock = StoreLock::acquire(store_root, LockMode::Write, lock_wait)
+        .await
+        .ok_or_else(|| CloneError::StoreLocked(store_root.to_path_buf()))?;
+    let bundle_file = File::open(bundle_path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to open {}: {}", bundle_path.display(), err));
+    let mut reader = BufReader::new(bundle_file);
+    let bundle = read_bundle_header(&mut reader).await?;
+    info!(
+        "applying bundle {} ({} dictionaries, {} chunks) to {}",
+        bundle_path.display(),
+        bundle.dictionaries.len(),
+        bundle.chunks.len(),
+        store_root.display()
+    );
+
+    // Validate the dictionaries before touching the store
+    let mut dictionaries: Vec<(PathBuf, storedict::StoreDictionary)> = Vec::new();
+    for bundle_dict in &bundle.dictionaries {
+        if bundle_dict.name.is_empty()
+            || bundle_dict.name.starts_with('.')
+            || bundle_dict.name.contains('/')
+        {
+            return Err(CloneError::InvalidBundle(format!(
+                "invalid dictionary name {:?}",
+                bundle_dict.name
+            )));
+        }
+        let dictionary = parse_dictionary(&bundle_dict.dictionary).map_err(|err| {
+            CloneError::InvalidBundle(format!("dictionary {}: {}", bundle_dict.name, err))
+        })?;
+        check_security_version(store_root, dictionary.security_version, allow_downgrade).map_err(
+            |minimum| CloneError::Downgrade {
+                version: dictionary.security_version,
+                minimum,
+            },
+        )?;
+        let path = store_root.join(&bundle_dict.name);
+        if !force_create {
+            if let Ok(existing) = tokio::fs::read(&path).await {
+                if existing != bundle_dict.dictionary {
+                    return Err(CloneError::DictionaryExists(path));
+                }
+            }
+        }
+        dictionaries.push((path, dictionary));
+    }
+
+    if let Some(base) = &bundle.base {
+        check_base(store_root, base, verify_present).await?;
+    }
+
+    // Zero chunks are left out of the bundle by the dictionaries skipping
+    // them, the ones in the bundle are needed as files by some dictionary.
+    let mut store = ChunkStore::new(store_root);
+
+    let mut bundle_chunks = ChunkIndex::new_empty();
+    let mut data_offset: u64 = 0;
+    for chunk in &bundle.chunks {
+        bundle_chunks.add_chunk(
+            HashSum::from_slice(&chunk.checksum[..]),
+            chunk.size as usize,
+            &[data_offset],
+        );
+        data_offset += chunk.size as u64;
+    }
+    let missing = store
+        .filter_present_chunks(verify_present, &bundle_chunks)
+        .await
+        .expect("filter chunks");
+    info!(
+        "{} chunks present in store, {} chunks to write",
+        bundle_chunks.len() - missing.len(),
+        missing.len()
+    );
+
+    let mut chunks_written = 0;
+    let mut bytes_written: u64 = 0;
+    for chunk in &bundle.chunks {
+        let hash = HashSum::from_slice(&chunk.checksum[..]);
+        let mut data = vec![0; chunk.size as usize];
+        reader
+            .read_exact(&mut data)
+            .await
+            .map_err(|_| CloneError::InvalidBundle("chunk data is truncated".to_string()))?;
+        if HashSum::b2_digest(&data, hash.len()) != hash {
+            return Err(CloneError::InvalidBundle(format!(
+                "chunk {} is corrupt",
+                hash
+            )));
+        }
+        if missing.contains(&hash) {
+            store
+                .write_chunk(&hash, &[], &data)
+                .await
+                .expect("failed to write chunk to store");
+            chunks_written += 1;
+            bytes_written += data.len() as u64;
+        }
+    }
+
+    // Only install dictionaries which are complete in store
+    for (path, dictionary) in &dictionaries {
+        let store = ChunkStore::for_dictionary(store_root, dictionary);
+        for cd in &dictionary.chunk_descriptors {
+            let hash = HashSum::from_slice(&cd.checksum[..]);
+            if !store
+                .chunk_present(false, &hash, cd.source_size as usize)
+                .await
+            {
+                return Err(CloneError::InvalidBundle(format!(
+                    "chunk {} of {} is missing",
+                    hash,
+                    path.display()
+                )));
+            }
+        }
+    }
+    for ((path, _), bundle_dict) in dictionaries.iter().zip(&bundle.dictionaries) {
+        write_dictionary_file(path, &bundle_dict.dictionary).await;
+        info!("installed {}", path.display());
+    }
+    info!(
+        "Successfully applied {}, wrote {} chunks ({})",
+        bundle_path.display(),
+        chunks_written,
+        size_str(bytes_written)
+    );
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use bitar::HashSum;
+
+use crate::manifest::resolve;
+use crate::verity::parse_hex;
+
+// Pointer to the current release of an update channel:
+//
+// {"url": "release_v2.ext4.cba", "version": "2.0.1", "checksum": "<hex>"}
+//
+// checksum is the source checksum of the release image. An optional
+// security_version is recorded in the dictionary. A relative url is
+// resolved against the pointer location.
+#[derive(Debug, Clone)]
+pub struct Pointer {
+    pub url: String,
+    pub version: String,
+    pub source_checksum: HashSum,
+    pub security_version: Option<u64>,
+}
+
+pub fn parse_pointer(buf: &[u8], base: &str) -> Result<Pointer, String> {
+    let pointer: serde_json::Value =
+        serde_json::from_slice(buf).map_err(|err| format!("invalid channel: {}", err))?;
+    let field = |name: &str| {
+        pointer[name]
+            .as_str()
+            .ok_or_else(|| format!("channel is missing {}", name))
+    };
+    Ok(Pointer {
+        url: resolve(base, field("url")?),
+        version: match &pointer["version"] {
+            serde_json::Value::String(version) => version.clone(),
+            serde_json::Value::Null => return Err("channel is missing version".to_string()),
+            version => version.to_string(),
+        },
+        source_checksum: HashSum::from_vec(
+            parse_hex(field("checksum")?).ok_or_else(|| "invalid channel checksum".to_string())?,
+        ),
+        security_version: pointer["security_version"].as_u64(),
+    })
+}
@@ -1,27 +1,125 @@
 use async_trait::async_trait;
-use bitar::{clone::CloneOutput, ChunkIndex, HashSum};
-use blake2::{Blake2b, Digest};
+use bitar::{
+    clone::{CloneFromReadableError, CloneOutput},
+    ChunkIndex, HashSum,
+};
+use futures::StreamExt;
 use log::*;
-use prost::Message;
+use serde_json::json;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tokio::fs::{create_dir_all, File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
 use url::Url;
 
+use crate::http::HttpOptions;
+use crate::peer::{PeerError, PeerStore};
+use crate::progress::Progress;
+use crate::reader::{RateLimit, RateLimited, RemoteReader};
 use crate::size_str::size_str;
-use crate::storedict;
-use crate::STORE_MAGIC;
+use crate::store::{build_store_header, check_security_version, parse_dictionary, ChunkStore};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+
+#[derive(Debug)]
+pub enum CloneError {
+    NotEnoughSpace { required: u64, available: u64 },
+    StoreUnavailable(PathBuf, String),
+    StoreLocked(PathBuf),
+    MirrorMismatch(String),
+    ArchiveUnavailable(String),
+    InvalidDictionary(String),
+    InvalidBundle(String),
+    ChunksMissing(usize),
+    IncompleteBase(String),
+    Downgrade { version: u64, minimum: u64 },
+    ChecksumMismatch { expected: String, actual: String },
+    DictionaryExists(PathBuf),
+    PublishFailed(PathBuf, String),
+}
+impl std::error::Error for CloneError {}
+impl std::fmt::Display for CloneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotEnoughSpace {
+                required,
+                available,
+            } => write!(
+                f,
+                "not enough space in store, clone requires {} but only {} is available",
+                size_str(*required),
+                size_str(*available)
+            ),
+            Self::StoreUnavailable(store_root, err) => {
+                write!(f, "failed to stat store {}: {}", store_root.display(), err)
+            }
+            Self::StoreLocked(store_root) => {
+                write!(f, "store {} is locked", store_root.display())
+            }
+            Self::MirrorMismatch(source) => {
+                write!(f, "mirror {} serves a different archive", source)
+            }
+            Self::ArchiveUnavailable(err) => write!(f, "failed to read archive: {}", err),
+            Self::InvalidDictionary(reason) => write!(f, "invalid source dictionary: {}", reason),
+            Self::InvalidBundle(reason) => write!(f, "invalid bundle: {}", reason),
+            Self::ChunksMissing(count) => {
+                write!(f, "{} chunks could not be fetched from source store", count)
+            }
+            Self::IncompleteBase(reason) => write!(f, "base of delta bundle: {}", reason),
+            Self::Downgrade { version, minimum } => write!(
+                f,
+                "security version {} is lower than {} of store (see --allow-downgrade)",
+                version, minimum
+            ),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "source checksum mismatch (expected {}, was {})",
+                expected, actual
+            ),
+            Self::DictionaryExists(path) => write!(f, "{} already exists in store", path.display()),
+            Self::PublishFailed(path, err) => {
+                write!(f, "failed to publish {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+impl CloneError {
+    /// Process exit code to use when failing with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::NotEnoughSpace { .. } => 3,
+            Self::StoreUnavailable(..)
+            | Self::ArchiveUnavailable(_)
+            | Self::DictionaryExists(_)
+            | Self::PublishFailed(..) => 1,
+            Self::StoreLocked(_) => 4,
+            Self::MirrorMismatch(_) => 5,
+            Self::InvalidDictionary(_) | Self::InvalidBundle(_) | Self::ChunksMissing(_) => 6,
+            Self::IncompleteBase(_) => 7,
+            Self::Downgrade { .. } => 8,
+            Self::ChecksumMismatch { .. } => 9,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum InputArchive {
     Local(std::path::PathBuf),
     Remote {
-        url: Url,
+        // Mirrors of the same archive, tried in order
+        urls: Vec<Url>,
         retries: u32,
         retry_delay: Duration,
         receive_timeout: Option<Duration>,
+        // Switch mirror if no data was received for this long
+        stall_timeout: Option<Duration>,
+        http: HttpOptions,
+        rate_limit: RateLimit,
+    },
+    // Dictionary in another ihop store
+    Store {
+        store: PeerStore,
+        name: String,
     },
 }
 
@@ -29,205 +127,349 @@ impl InputArchive {
     pub fn source(&self) -> String {
         match self {
             Self::Local(p) => format!("{}", p.display()),
-            Self::Remote { url, .. } => url.to_string(),
+            Self::Remote { urls, .. } if urls.len() > 1 => {
+                format!("{} (+{} mirrors)", urls[0], urls.len() - 1)
+            }
+            Self::Remote { urls, .. } => urls[0].to_string(),
+            Self::Store { store, name } => format!("{} in store {}", name, store.source()),
         }
     }
 }
 
-pub fn build_store_header(dictionary: &storedict::StoreDictionary) -> Vec<u8> {
-    let mut header: Vec<u8> = vec![];
-    let mut hasher = Blake2b::new();
-    let mut dictionary_buf: Vec<u8> = Vec::new();
-
-    dictionary
-        .encode(&mut dictionary_buf)
-        .expect("encode dictionary");
+fn available_space(store_root: &Path) -> Result<u64, CloneError> {
+    let stat = nix::sys::statvfs::statvfs(store_root)
+        .map_err(|err| CloneError::StoreUnavailable(store_root.to_path_buf(), err.to_string()))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
 
-    // File magic indicating bita archive version 1
-    header.extend(STORE_MAGIC);
-    header.extend(&(dictionary_buf.len() as u64).to_le_bytes());
-    header.extend(dictionary_buf);
+// Clone output used on dry run, drops all chunks
+struct NullOutput;
 
-    // Create and store hash of full header
-    hasher.update(&header);
-    header.extend(&hasher.finalize());
-    header
+#[async_trait]
+impl CloneOutput for NullOutput {
+    type Error = std::io::Error;
+    async fn write_chunk(
+        &mut self,
+        _hash: &HashSum,
+        _offsets: &[u64],
+        _buf: &[u8],
+    ) -> Result<(), std::io::Error> {
+        Ok(())
+    }
 }
 
-pub fn chunk_path_from_hash(hash: &HashSum) -> PathBuf {
-    let subdir_bytes = 2;
-    let mut subdir_name = String::with_capacity(subdir_bytes * 2);
-    hash.slice()[..subdir_bytes]
-        .iter()
-        .for_each(|b| subdir_name.push_str(&format!("{:02x}", b)));
-    Path::new("chunks")
-        .join(subdir_name)
-        .join(&format!("{}", hash))
-        .with_extension("chunk")
+// Clone output keeping track of the chunks written and reporting progress
+struct TrackedOutput<'a, C> {
+    inner: &'a mut C,
+    written: Vec<HashSum>,
+    progress: &'a Progress,
+    // Where the chunks come from
+    from: &'a str,
+    // Number of bytes fetched per chunk, if fetched
+    fetched_sizes: Option<&'a HashMap<HashSum, u64>>,
 }
 
-#[derive(Clone, Debug)]
-struct ChunkStore {
-    root_path: PathBuf,
-}
-impl ChunkStore {
-    fn new(root_path: &Path) -> Self {
+impl<'a, C> TrackedOutput<'a, C> {
+    fn new(
+        inner: &'a mut C,
+        progress: &'a Progress,
+        from: &'a str,
+        fetched_sizes: Option<&'a HashMap<HashSum, u64>>,
+    ) -> Self {
         Self {
-            root_path: root_path.to_path_buf(),
+            inner,
+            written: Vec::new(),
+            progress,
+            from,
+            fetched_sizes,
         }
     }
-    async fn filter_present_chunks(
-        &self,
-        verify: bool,
-        chunks: &ChunkIndex,
-    ) -> Result<ChunkIndex, std::io::Error> {
-        let mut new_index = ChunkIndex::new_empty();
-        for (hash, location) in chunks.iter_chunks() {
-            // Test if chunk file exists
-            let chunk_path = self.root_path.join(chunk_path_from_hash(hash));
-            match OpenOptions::new().read(true).open(chunk_path).await {
-                Ok(mut chunk_file) => {
-                    if verify {
-                        let mut chunk_buf = Vec::with_capacity(location.size());
-                        if match chunk_file.read_to_end(&mut chunk_buf).await {
-                            Ok(_) => HashSum::b2_digest(&chunk_buf, hash.len()) != *hash,
-                            Err(_err) => false,
-                        } {
-                            // Chunk present but seems corrupt
-                            warn!("Chunk {} corrupt, will be re-fetched", hash);
-                            new_index.add_chunk(hash.clone(), location.size(), location.offsets());
-                        }
-                    }
-                }
-                Err(_err) => {
-                    // Chunk is not present
-                    new_index.add_chunk(hash.clone(), location.size(), location.offsets());
-                }
-            }
-        }
-        Ok(new_index)
+}
+
+#[async_trait]
+impl<C> CloneOutput for TrackedOutput<'_, C>
+where
+    C: CloneOutput + Send,
+{
+    type Error = C::Error;
+    async fn write_chunk(
+        &mut self,
+        hash: &HashSum,
+        offsets: &[u64],
+        buf: &[u8],
+    ) -> Result<(), C::Error> {
+        self.inner.write_chunk(hash, offsets, buf).await?;
+        let fetched = self
+            .fetched_sizes
+            .and_then(|sizes| sizes.get(hash).copied())
+            .unwrap_or(0);
+        self.progress
+            .chunk_written(hash, buf.len(), self.from, fetched);
+        self.written.push(hash.clone());
+        Ok(())
     }
+}
 
-    fn chunker_config_to_params(
-        conf: &bitar::chunker::Config,
-        chunk_hash_length: u32,
-    ) -> storedict::ChunkerParameters {
-        match conf {
-            bitar::chunker::Config::BuzHash(hash_config) => storedict::ChunkerParameters {
-                chunk_hash_length,
-                chunk_filter_bits: hash_config.filter_bits.bits(),
-                chunking_algorithm: storedict::chunker_parameters::ChunkingAlgorithm::Buzhash
-                    as i32,
-                min_chunk_size: hash_config.min_chunk_size as u32,
-                max_chunk_size: hash_config.max_chunk_size as u32,
-                rolling_hash_window_size: hash_config.window_size as u32,
-            },
-            bitar::chunker::Config::RollSum(hash_config) => storedict::ChunkerParameters {
-                chunk_hash_length,
-                chunk_filter_bits: hash_config.filter_bits.bits(),
-                chunking_algorithm: storedict::chunker_parameters::ChunkingAlgorithm::Rollsum
-                    as i32,
-                min_chunk_size: hash_config.min_chunk_size as u32,
-                max_chunk_size: hash_config.max_chunk_size as u32,
-                rolling_hash_window_size: hash_config.window_size as u32,
-            },
-            bitar::chunker::Config::FixedSize(fixed_size) => storedict::ChunkerParameters {
-                chunk_hash_length,
-                chunk_filter_bits: 0,
-                chunking_algorithm: storedict::chunker_parameters::ChunkingAlgorithm::FixedSize
-                    as i32,
-                min_chunk_size: 0,
-                max_chunk_size: *fixed_size as u32,
-                rolling_hash_window_size: 0,
-            },
+// Sum of archive (compressed) size of the given chunks
+fn archive_size_of(archive: &bitar::Archive, chunks: &ChunkIndex) -> u64 {
+    archive
+        .chunk_descriptors()
+        .iter()
+        .map(|cd| {
+            if chunks.contains(&cd.checksum) {
+                cd.archive_size as u64
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+async fn clone_from_seeds<C>(
+    seeds: &[PathBuf],
+    archive: &bitar::Archive,
+    chunks_left: &mut ChunkIndex,
+    output: &mut C,
+    progress: &Progress,
+) where
+    C: CloneOutput,
+    C::Error: std::fmt::Debug,
+{
+    let clone_opts = bitar::clone::Options::default();
+    for seed in seeds {
+        if chunks_left.is_empty() {
+            break;
         }
+        // Seeds are only used to fetch less, skip the ones failing
+        let mut seed_file = match File::open(seed).await {
+            Ok(seed_file) => seed_file,
+            Err(err) => {
+                warn!("skipping seed {}: {}", seed.display(), err);
+                continue;
+            }
+        };
+        let chunks_before = chunks_left.len();
+        let bytes_used = match bitar::clone::from_readable(
+            &clone_opts,
+            &mut seed_file,
+            archive,
+            chunks_left,
+            output,
+        )
+        .await
+        {
+            Ok(bytes_used) => bytes_used,
+            Err(CloneFromReadableError::SourceError(err)) => {
+                // Chunks already taken from the seed have been written
+                warn!(
+                    "failed to read seed {} ({}), used {} chunks from it",
+                    seed.display(),
+                    err,
+                    chunks_before - chunks_left.len()
+                );
+                continue;
+            }
+            Err(err) => panic!("failed to clone from seed {}: {:?}", seed.display(), err),
+        };
+        info!(
+            "used {} chunks ({}) from seed {}",
+            chunks_before - chunks_left.len(),
+            size_str(bytes_used),
+            seed.display()
+        );
+        progress.event(
+            "seed_used",
+            json!({
+                "seed": seed.display().to_string(),
+                "chunks": chunks_before - chunks_left.len(),
+                "bytes": bytes_used,
+            }),
+        );
     }
+}
 
-    fn dictionary(&self, archive: &bitar::Archive) -> storedict::StoreDictionary {
-        let mut chunk_to_index: HashMap<HashSum, usize> = HashMap::new();
-        let descriptors = archive
-            .chunk_descriptors()
-            .iter()
-            .enumerate()
-            .map(|(index, desc)| {
-                chunk_to_index.insert(desc.checksum.clone(), index);
-                storedict::ChunkDescriptor {
-                    checksum: desc.checksum.to_vec(),
-                    source_size: desc.source_size,
-                }
-            })
+// Number of chunk requests in flight towards a peer store
+const PEER_FETCH_CONCURRENCY: usize = 8;
+
+// Fetch the chunks left from other stores, by hash. Failing peers are
+// skipped, whatever is left is fetched from the archive.
+async fn clone_from_peers<C>(
+    peers: &[PeerStore],
+    chunks_left: &mut ChunkIndex,
+    output: &mut C,
+    progress: &Progress,
+) where
+    C: CloneOutput,
+    C::Error: std::error::Error,
+{
+    for peer in peers {
+        if chunks_left.is_empty() {
+            break;
+        }
+        let wanted: Vec<(HashSum, Vec<u64>)> = chunks_left
+            .iter_chunks()
+            .map(|(hash, location)| (hash.clone(), location.offsets().to_vec()))
             .collect();
-        storedict::StoreDictionary {
-            application_version: crate::PKG_VERSION.to_string(),
-            chunker_params: Some(Self::chunker_config_to_params(
-                archive.chunker_config(),
-                archive.chunk_hash_length() as u32,
-            )),
-            source_checksum: archive.source_checksum().to_vec(),
-            source_total_size: archive.total_source_size(),
-            source_order: archive
-                .iter_source_chunks()
-                .map(|(_, cd)| *chunk_to_index.get(&cd.checksum).unwrap() as u32)
-                .collect(),
-            chunk_descriptors: descriptors,
+        let mut fetches = futures::stream::iter(wanted.iter())
+            .map(|(hash, offsets)| async move { (hash, offsets, peer.fetch_chunk(hash).await) })
+            .buffer_unordered(PEER_FETCH_CONCURRENCY);
+        let mut chunks_used = 0;
+        let mut bytes_used: u64 = 0;
+        while let Some((hash, offsets, result)) = fetches.next().await {
+            match result {
+                Ok(Some(chunk)) => {
+                    output
+                        .write_chunk(hash, offsets, &chunk)
+                        .await
+                        .unwrap_or_else(|err| panic!("failed to write chunk to store: {}", err));
+                    chunks_left.remove(hash);
+                    chunks_used += 1;
+                    bytes_used += chunk.len() as u64;
+                }
+                Ok(None) => {}
+                Err(err @ PeerError::Corrupt(_)) => {
+                    warn!("skipping chunk from peer {}: {}", peer.source(), err)
+                }
+                Err(err) => {
+                    warn!("failed to fetch from peer {}: {}", peer.source(), err);
+                    break;
+                }
+            }
         }
+        info!(
+            "used {} chunks ({}) from peer {}",
+            chunks_used,
+            size_str(bytes_used),
+            peer.source()
+        );
+        progress.event(
+            "peer_used",
+            json!({
+                "peer": peer.source(),
+                "chunks": chunks_used,
+                "bytes": bytes_used,
+            }),
+        );
     }
 }
 
-#[async_trait]
-impl CloneOutput for ChunkStore {
-    type Error = std::io::Error;
-    async fn write_chunk(
-        &mut self,
-        hash: &HashSum,
-        _offsets: &[u64],
-        buf: &[u8],
-    ) -> Result<(), std::io::Error> {
-        let chunk_path = self.root_path.join(chunk_path_from_hash(hash));
-        create_dir_all(chunk_path.parent().expect("chunk subdir")).await?;
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&chunk_path)
-            .await?;
-        debug!("write chunk {} to {}", hash, chunk_path.display());
-        file.write_all(&buf[..]).await.expect("write chunk file");
-        Ok(())
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Overwrite the output dictionary if it exists
+    pub force_create: bool,
+    // Verify checksum of chunks already in store
+    pub verify_present: bool,
+    // Only report what would be done, write nothing
+    pub dry_run: bool,
+    pub lock_wait: LockWait,
+    // Files or devices to scan for chunks before fetching from archive
+    pub seeds: Vec<PathBuf>,
+    // Stores to fetch chunks from before fetching from archive
+    pub peers: Vec<PeerStore>,
+    // Security version of the release of an archive, from the channel or
+    // manifest pointing at it. Recorded in the dictionary.
+    pub security_version: u64,
+    // Clone releases older than the store security version
+    pub allow_downgrade: bool,
+    // Expected hash of the source image
+    pub source_checksum: Option<HashSum>,
+}
+
+fn check_source_checksum(opts: &Options, source_checksum: &HashSum) -> Result<(), CloneError> {
+    match &opts.source_checksum {
+        Some(expected) if expected != source_checksum => Err(CloneError::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual: source_checksum.to_string(),
+        }),
+        _ => Ok(()),
     }
 }
 
 async fn clone_with_reader<R>(
     store_root: &Path,
-    mut reader: R,
-    mut output_dict: File,
-    verify_present: bool,
-) where
+    mirrors: Vec<(String, R)>,
+    // Times to retry a failing transfer before moving on to the next mirror
+    retries: u32,
+    retry_delay: Duration,
+    output_dict: Option<File>,
+    opts: &Options,
+    progress: &Progress,
+) -> Result<(), CloneError>
+where
     R: bitar::Reader,
-    R::Error: std::fmt::Debug + Send + Sync + 'static,
+    R::Error: std::error::Error + Send + Sync + 'static,
 {
-    let archive = bitar::Archive::try_init(&mut reader)
-        .await
-        .expect("init archive");
+    // All mirrors must serve the very same archive
+    let mut archive: Option<bitar::Archive> = None;
+    let mut readers = Vec::new();
+    let mut last_error = None;
+    for (source, mut reader) in mirrors {
+        let mut retries_left = retries;
+        let result = loop {
+            match bitar::Archive::try_init(&mut reader).await {
+                Err(err) if retries_left > 0 => {
+                    retries_left -= 1;
+                    warn!("failed to read archive from {} ({}), retrying", source, err);
+                    progress.event(
+                        "retry",
+                        json!({
+                            "failed_source": source,
+                            "error": err.to_string(),
+                            "source": source,
+                        }),
+                    );
+                    tokio::time::delay_for(retry_delay).await;
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(mirror_archive) => {
+                match &archive {
+                    Some(archive)
+                        if archive.header_checksum() != mirror_archive.header_checksum() =>
+                    {
+                        return Err(CloneError::MirrorMismatch(source));
+                    }
+                    Some(_) => {}
+                    None => archive = Some(mirror_archive),
+                }
+                readers.push((source, reader));
+            }
+            Err(err) => {
+                warn!("failed to read archive from {}: {}", source, err);
+                last_error = Some(format!("{}: {}", source, err));
+            }
+        }
+    }
+    let archive = archive.ok_or_else(|| {
+        CloneError::ArchiveUnavailable(last_error.unwrap_or_else(|| "no source".to_string()))
+    })?;
+    check_source_checksum(opts, archive.source_checksum())?;
+    progress.event(
+        "archive_opened",
+        json!({
+            "source": readers[0].0,
+            "mirrors": readers.len(),
+            "chunks": archive.unique_chunks(),
+            "source_size": archive.total_source_size(),
+            "archive_size": archive.compressed_size(),
+        }),
+    );
     let chunks_to_get = archive.build_source_index();
 
-    let mut store = ChunkStore::new(store_root);
+    let mut store =
+        ChunkStore::new(store_root).with_zero_chunks(&ChunkStore::chunker_config_to_params(
+            archive.chunker_config(),
+            archive.chunk_hash_length() as u32,
+        ));
     let clone_opts = bitar::clone::Options::default();
     // Don't fetch chunks already in store
     let mut chunks_left = store
-        .filter_present_chunks(verify_present, &chunks_to_get)
+        .filter_present_chunks(opts.verify_present, &chunks_to_get)
         .await
         .expect("filter chunks");
-    let bytes_to_fetch: u64 = archive
-        .chunk_descriptors()
-        .iter()
-        .map(|cd| {
-            if chunks_left.contains(&cd.checksum) {
-                cd.archive_size as u64
-            } else {
-                0
-            }
-        })
-        .sum();
+    let bytes_to_fetch = archive_size_of(&archive, &chunks_left);
 
     info!(
         "{} chunks present in store, {} chunks to fetch ({} / {})",
@@ -237,85 +479,501 @@ async fn clone_with_reader<R>(
         size_str(archive.compressed_size())
     );
 
-    // Fetch the rest of the chunks from archive
-    bitar::clone::from_archive(
-        &clone_opts,
-        &mut reader,
-        &archive,
-        &mut chunks_left,
-        &mut store,
-    )
-    .await
-    .expect("clone from archive");
+    let mut dictionary = store.dictionary(&archive);
+    dictionary.security_version = opts.security_version;
+    let header_buf = build_store_header(&dictionary);
+    let bytes_to_write: u64 = chunks_left
+        .iter_chunks()
+        .map(|(_hash, location)| location.size() as u64)
+        .sum();
+    let required_space = bytes_to_write + header_buf.len() as u64;
+    let available_space = available_space(store_root)?;
+    progress.event(
+        "store_checked",
+        json!({
+            "present": chunks_to_get.len() - chunks_left.len(),
+            "missing": chunks_left.len(),
+            "bytes_to_fetch": bytes_to_fetch,
+            "bytes_to_write": bytes_to_write,
+            "required_space": required_space,
+            "available_space": available_space,
+        }),
+    );
+    let mut output_dict = match output_dict {
+        Some(output_dict) => output_dict,
+        None => {
+            // Dry run, only report what a clone would do
+            clone_from_seeds(
+                &opts.seeds,
+                &archive,
+                &mut chunks_left,
+                &mut NullOutput,
+                progress,
+            )
+            .await;
+            progress.event(
+                "dry_run",
+                json!({
+                    "chunks_to_fetch": chunks_left.len(),
+                    "bytes_to_fetch": archive_size_of(&archive, &chunks_left),
+                }),
+            );
+            info!(
+                "dry run: would fetch {} chunks ({}), write {} to store and need {} of free space ({} available)",
+                chunks_left.len(),
+                size_str(archive_size_of(&archive, &chunks_left)),
+                size_str(bytes_to_write),
+                size_str(required_space),
+                size_str(available_space)
+            );
+            return Ok(());
+        }
+    };
+    if required_space > available_space {
+        return Err(CloneError::NotEnoughSpace {
+            required: required_space,
+            available: available_space,
+        });
+    }
+
+    // Use what we can find in seeds before going to the archive
+    if !opts.seeds.is_empty() {
+        let mut output = TrackedOutput::new(&mut store, progress, "seed", None);
+        clone_from_seeds(
+            &opts.seeds,
+            &archive,
+            &mut chunks_left,
+            &mut output,
+            progress,
+        )
+        .await;
+        info!(
+            "{} chunks left to fetch ({})",
+            chunks_left.len(),
+            size_str(archive_size_of(&archive, &chunks_left))
+        );
+    }
+
+    // Then what we can get from peer stores
+    if !opts.peers.is_empty() {
+        let chunk_sizes: HashMap<HashSum, u64> = chunks_left
+            .iter_chunks()
+            .map(|(hash, location)| (hash.clone(), location.size() as u64))
+            .collect();
+        let mut output = TrackedOutput::new(&mut store, progress, "peer", Some(&chunk_sizes));
+        clone_from_peers(&opts.peers, &mut chunks_left, &mut output, progress).await;
+        info!(
+            "{} chunks left to fetch ({})",
+            chunks_left.len(),
+            size_str(archive_size_of(&archive, &chunks_left))
+        );
+    }
+
+    // Fetch the rest of the chunks from archive. On failure retry and then
+    // continue with the chunks left from the next mirror.
+    let fetched_sizes: HashMap<HashSum, u64> = archive
+        .chunk_descriptors()
+        .iter()
+        .map(|cd| (cd.checksum.clone(), cd.archive_size as u64))
+        .collect();
+    let mut mirror = 0;
+    let mut failures = 0;
+    let mut retries_left = retries;
+    loop {
+        let mut output = TrackedOutput::new(&mut store, progress, "archive", Some(&fetched_sizes));
+        let result = bitar::clone::from_archive(
+            &clone_opts,
+            &mut readers[mirror].1,
+            &archive,
+            &mut chunks_left,
+            &mut output,
+        )
+        .await;
+        if !output.written.is_empty() {
+            failures = 0;
+            retries_left = retries;
+        }
+        for hash in &output.written {
+            chunks_left.remove(hash);
+        }
+        match result {
+            Ok(_) => break,
+            Err(bitar::clone::CloneFromArchiveError::TargetError(err)) => {
+                panic!("failed to write chunk to store: {}", err)
+            }
+            Err(err) => {
+                let failed_source = readers[mirror].0.clone();
+                if retries_left > 0 {
+                    retries_left -= 1;
+                    tokio::time::delay_for(retry_delay).await;
+                } else {
+                    failures += 1;
+                    if failures >= readers.len() {
+                        return Err(CloneError::ArchiveUnavailable(format!(
+                            "{}: {}",
+                            failed_source, err
+                        )));
+                    }
+                    mirror = (mirror + 1) % readers.len();
+                    retries_left = retries;
+                }
+                warn!(
+                    "fetch from {} failed ({}), fetching {} chunks left from {}",
+                    failed_source,
+                    err,
+                    chunks_left.len(),
+                    readers[mirror].0
+                );
+                progress.event(
+                    "retry",
+                    json!({
+                        "failed_source": failed_source,
+                        "error": err.to_string(),
+                        "source": readers[mirror].0,
+                        "chunks_left": chunks_left.len(),
+                    }),
+                );
+            }
+        }
+    }
 
     // Write the store dictionary file
-    let header_buf = build_store_header(&store.dictionary(&archive));
     output_dict
         .write_all(&header_buf[..])
         .await
         .expect("write output file");
+    output_dict.flush().await.expect("flush output file");
+    progress.done();
+    Ok(())
+}
+
+// Clone a dictionary from another store, fetching the missing chunks by hash.
+async fn clone_from_store(
+    store_root: &Path,
+    source: &PeerStore,
+    name: &str,
+    output_dict: Option<File>,
+    opts: &Options,
+    progress: &Progress,
+) -> Result<(), CloneError> {
+    let dictionary_buf = source
+        .fetch_file(name)
+        .await
+        .map_err(|err| CloneError::ArchiveUnavailable(format!("{}: {}", name, err)))?
+        .ok_or_else(|| CloneError::InvalidDictionary(format!("{} not found", name)))?;
+    let mut dictionary = parse_dictionary(&dictionary_buf)
+        .map_err(|err| CloneError::InvalidDictionary(err.to_string()))?;
+    let dictionary_buf = if dictionary.verity.take().is_some() {
+        // The hash tree isn't cloned, so neither is the reference to it
+        info!(
+            "not keeping verity root hash of {}, hash tree is not cloned",
+            name
+        );
+        build_store_header(&dictionary)
+    } else {
+        dictionary_buf
+    };
+    check_source_checksum(opts, &HashSum::from_slice(&dictionary.source_checksum[..]))?;
+    check_security_version(
+        store_root,
+        dictionary.security_version,
+        opts.allow_downgrade,
+    )
+    .map_err(|minimum| CloneError::Downgrade {
+        version: dictionary.security_version,
+        minimum,
+    })?;
+    progress.event(
+        "dictionary_opened",
+        json!({
+            "source": source.source(),
+            "chunks": dictionary.chunk_descriptors.len(),
+            "source_size": dictionary.source_total_size,
+        }),
+    );
+    if !opts.seeds.is_empty() {
+        warn!("seeds are not used when cloning from a store");
+    }
+
+    let mut chunks_to_get = ChunkIndex::new_empty();
+    let mut offset: u64 = 0;
+    for index in &dictionary.source_order {
+        let cd = &dictionary.chunk_descriptors[*index as usize];
+        chunks_to_get.add_chunk(
+            HashSum::from_slice(&cd.checksum[..]),
+            cd.source_size as usize,
+            &[offset],
+        );
+        offset += cd.source_size as u64;
+    }
+    let mut store = ChunkStore::for_dictionary(store_root, &dictionary);
+    let mut chunks_left = store
+        .filter_present_chunks(opts.verify_present, &chunks_to_get)
+        .await
+        .expect("filter chunks");
+    let bytes_to_write: u64 = chunks_left
+        .iter_chunks()
+        .map(|(_hash, location)| location.size() as u64)
+        .sum();
+    let required_space = bytes_to_write + dictionary_buf.len() as u64;
+    let available_space = available_space(store_root)?;
+    info!(
+        "{} chunks present in store, {} chunks to fetch ({})",
+        chunks_to_get.len() - chunks_left.len(),
+        chunks_left.len(),
+        size_str(bytes_to_write)
+    );
+    progress.event(
+        "store_checked",
+        json!({
+            "present": chunks_to_get.len() - chunks_left.len(),
+            "missing": chunks_left.len(),
+            "bytes_to_fetch": bytes_to_write,
+            "bytes_to_write": bytes_to_write,
+            "required_space": required_space,
+            "available_space": available_space,
+        }),
+    );
+    let mut output_dict = match output_dict {
+        Some(output_dict) => output_dict,
+        None => {
+            progress.event(
+                "dry_run",
+                json!({
+                    "chunks_to_fetch": chunks_left.len(),
+                    "bytes_to_fetch": bytes_to_write,
+                }),
+            );
+            info!(
+                "dry run: would fetch and write {} chunks ({}) to store and need {} of free space ({} available)",
+                chunks_left.len(),
+                size_str(bytes_to_write),
+                size_str(required_space),
+                size_str(available_space)
+            );
+            return Ok(());
+        }
+    };
+    if required_space > available_space {
+        return Err(CloneError::NotEnoughSpace {
+            required: required_space,
+            available: available_space,
+        });
+    }
+
+    // Peers first, then the source store itself
+    let mut stores = opts.peers.clone();
+    stores.push(source.clone());
+    let chunk_sizes: HashMap<HashSum, u64> = chunks_left
+        .iter_chunks()
+        .map(|(hash, location)| (hash.clone(), location.size() as u64))
+        .collect();
+    let mut output = TrackedOutput::new(&mut store, progress, "store", Some(&chunk_sizes));
+    clone_from_peers(&stores, &mut chunks_left, &mut output, progress).await;
+    if !chunks_left.is_empty() {
+        return Err(CloneError::ChunksMissing(chunks_left.len()));
+    }
+
+    // The dictionary is the same as in the source store (but for verity)
+    output_dict
+        .write_all(&dictionary_buf)
+        .await
+        .expect("write output file");
+    output_dict.flush().await.expect("flush output file");
+    progress.done();
+    Ok(())
+}
+
+async fn read_source_checksum<R>(source: &str, mut reader: R) -> Result<HashSum, String>
+where
+    R: bitar::Reader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    bitar::Archive::try_init(&mut reader)
+        .await
+        .map(|archive| archive.source_checksum().clone())
+        .map_err(|err| format!("{}: {}", source, err))
+}
+
+// Source checksum of the release of an input, read from the archive header
+// (or dictionary) only. Used to tell if a release is in store before cloning.
+pub async fn source_checksum_of(input: &InputArchive) -> Result<HashSum, CloneError> {
+    match input {
+        InputArchive::Local(path) => {
+            let source = path.display().to_string();
+            let reader = File::open(path)
+                .await
+                .map_err(|err| CloneError::ArchiveUnavailable(format!("{}: {}", source, err)))?;
+            read_source_checksum(&source, reader)
+                .await
+                .map_err(CloneError::ArchiveUnavailable)
+        }
+        InputArchive::Remote {
+            urls,
+            receive_timeout,
+            stall_timeout,
+            http,
+            ..
+        } => {
+            let client = http.client();
+            let mut last_error = None;
+            for url in urls {
+                let mut request = client.get(url.clone());
+                if let Some(timeout) = receive_timeout {
+                    request = request.timeout(*timeout);
+                }
+                let reader = RemoteReader::new(request, *stall_timeout);
+                match read_source_checksum(url.as_str(), reader).await {
+                    Ok(source_checksum) => return Ok(source_checksum),
+                    Err(err) => {
+                        warn!("failed to read archive from {}", err);
+                        last_error = Some(err);
+                    }
+                }
+            }
+            Err(CloneError::ArchiveUnavailable(
+                last_error.unwrap_or_else(|| "no source".to_string()),
+            ))
+        }
+        InputArchive::Store { store, name } => {
+            let dictionary_buf = store
+                .fetch_file(name)
+                .await
+                .map_err(|err| CloneError::ArchiveUnavailable(format!("{}: {}", name, err)))?
+                .ok_or_else(|| CloneError::InvalidDictionary(format!("{} not found", name)))?;
+            parse_dictionary(&dictionary_buf)
+                .map(|dictionary| HashSum::from_vec(dictionary.source_checksum))
+                .map_err(|err| CloneError::InvalidDictionary(err.to_string()))
+        }
+    }
 }
 
 pub async fn clone(
     input: InputArchive,
     output: &Path,
     store_root: &Path,
-    force_create: bool,
-    verify_present: bool,
-) {
+    opts: &Options,
+    progress: &Progress,
+) -> Result<(), CloneError> {
     let input_source = input.source();
 
-    let output_dict = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(force_create)
-        .create_new(!force_create)
-        .open(&output)
-        .await
-        .expect("open output file");
+    // Keep others from modifying the store while cloning. Dry run is read only
+    // and doesn't need a lock.
+    let _lock = if opts.dry_run {
+        None
+    } else {
+        Some(
+            StoreLock::acquire(store_root, LockMode::Write, opts.lock_wait)
+                .await
+                .ok_or_else(|| CloneError::StoreLocked(store_root.to_path_buf()))?,
+        )
+    };
+
+    if let InputArchive::Local(_) | InputArchive::Remote { .. } = input {
+        check_security_version(store_root, opts.security_version, opts.allow_downgrade).map_err(
+            |minimum| CloneError::Downgrade {
+                version: opts.security_version,
+                minimum,
+            },
+        )?;
+    }
+
+    let output_dict = if opts.dry_run {
+        None
+    } else {
+        Some(
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(opts.force_create)
+                .create_new(!opts.force_create)
+                .open(&output)
+                .await
+                .expect("open output file"),
+        )
+    };
 
     //let mut reader = input.new_reader().await;
     info!(
-        "cloning archive {} to {} (chunks at {}/chunks)",
+        "{} archive {} to {} (chunks at {}/chunks)",
+        if opts.dry_run {
+            "planning clone of"
+        } else {
+            "cloning"
+        },
         input_source,
         output.display(),
         store_root.display()
     );
-    match input {
+    let result = match input {
         InputArchive::Local(path) => {
+            let reader = File::open(&path)
+                .await
+                .expect("failed to open local archive");
             clone_with_reader(
                 store_root,
-                File::open(path)
-                    .await
-                    .expect("failed to open local archive"),
+                vec![(input_source.clone(), reader)],
+                0,
+                Duration::from_secs(0),
                 output_dict,
-                verify_present,
+                opts,
+                progress,
             )
             .await
         }
         InputArchive::Remote {
-            url,
+            urls,
             retries,
             retry_delay,
             receive_timeout,
+            stall_timeout,
+            http,
+            rate_limit,
         } => {
-            let mut request = reqwest::Client::new().get(url);
-            if let Some(timeout) = receive_timeout {
-                request = request.timeout(timeout);
-            }
+            let client = http.client();
+            let mirrors = urls
+                .into_iter()
+                .map(|url| {
+                    let source = url.to_string();
+                    let mut request = client.get(url);
+                    if let Some(timeout) = receive_timeout {
+                        request = request.timeout(timeout);
+                    }
+                    // Retried by clone_with_reader, which reports each retry
+                    let reader = RemoteReader::new(request, stall_timeout);
+                    (source, RateLimited::new(reader, rate_limit.clone()))
+                })
+                .collect();
             clone_with_reader(
                 store_root,
-                bitar::ReaderRemote::from_request(request)
-                    .retries(retries)
-                    .retry_delay(retry_delay),
+                mirrors,
+                retries,
+                retry_delay,
                 output_dict,
-                verify_present,
+                opts,
+                progress,
             )
             .await
         }
+        InputArchive::Store { store, name } => {
+            clone_from_store(store_root, &store, &name, output_dict, opts, progress).await
+        }
+    };
+    if let Err(err) = result {
+        progress.error(&err.to_string(), err.exit_code());
+        if !opts.dry_run && !opts.force_create {
+            // Don't leave the empty dictionary behind
+            tokio::fs::remove_file(&output)
+                .await
+                .expect("remove output file");
+        }
+        return Err(err);
     }
-    info!(
-        "Successfully cloned {} to {}",
-        input_source,
-        output.display()
-    );
+    if !opts.dry_run {
+        info!(
+            "Successfully cloned {} to {}",
+            input_source,
+            output.display()
+        );
+    }
+    Ok(())
 }
@@ -0,0 +1,183 @@
+use bitar::HashSum;
+use log::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::size_str::size_str;
+use crate::store::{read_dictionary, ChunkStore};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+use crate::storedict;
+
+// Chunk of the image placed at an offset
+struct Span {
+    offset: u64,
+    descriptor: usize,
+}
+
+async fn device_size(device: &mut File) -> u64 {
+    let size = device.seek(SeekFrom::End(0)).await.expect("seek device");
+    device.seek(SeekFrom::Start(0)).await.expect("seek device");
+    size
+}
+
+// Read up to size bytes at offset, less if the device ends before.
+async fn read_span(device: &mut File, offset: u64, size: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(size);
+    device
+        .seek(SeekFrom::Start(offset))
+        .await
+        .expect("seek device");
+    device
+        .take(size as u64)
+        .read_to_end(&mut buf)
+        .await
+        .expect("read device");
+    buf
+}
+
+// Find the chunks of the image which the device doesn't hold. Each chunk
+// span on the device is hashed and compared with the chunk hash.
+async fn mismatching_spans(
+    device: &mut File,
+    dictionary: &storedict::StoreDictionary,
+) -> Vec<Span> {
+    let mut mismatching = Vec::new();
+    let mut offset: u64 = 0;
+    for index in &dictionary.source_order {
+        let cd = &dictionary.chunk_descriptors[*index as usize];
+        let buf = read_span(device, offset, cd.source_size as usize).await;
+        if buf.len() != cd.source_size as usize
+            || HashSum::b2_digest(&buf, cd.checksum.len()).slice() != &cd.checksum[..]
+        {
+            mismatching.push(Span {
+                offset,
+                descriptor: *index as usize,
+            });
+        }
+        offset += cd.source_size as u64;
+    }
+    mismatching
+}
+
+// Merge adjacent spans into ranges of (offset, size).
+fn span_ranges(spans: &[Span], dictionary: &storedict::StoreDictionary) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for span in spans {
+        let size = dictionary.chunk_descriptors[span.descriptor].source_size as u64;
+        match ranges.last_mut() {
+            Some((offset, range_size)) if *offset + *range_size == span.offset => {
+                *range_size += size
+            }
+            _ => ranges.push((span.offset, size)),
+        }
+    }
+    ranges
+}
+
+// Write the image of a dictionary to a device, only touching the parts
+// which differ from what is already there.
+pub async fn flash(dictionary_path: &Path, device_path: &Path, lock_wait: LockWait) {
+    let store_root = dictionary_path.parent().expect("store root");
+    let _lock = StoreLock::acquire(store_root, LockMode::Shared, lock_wait)
+        .await
+        .unwrap_or_else(|| panic!("store {} is locked", store_root.display()));
+    let dictionary = read_dictionary(dictionary_path).await;
+    let store = ChunkStore::for_dictionary(store_root, &dictionary);
+    let mut device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to open {}: {}", device_path.display(), err));
+    let is_file = device.metadata().await.expect("stat device").is_file();
+    let size = device_size(&mut device).await;
+    if !is_file && size < dictionary.source_total_size {
+        panic!(
+            "image ({}) does not fit on {} ({})",
+            size_str(dictionary.source_total_size),
+            device_path.display(),
+            size_str(size)
+        );
+    }
+    info!(
+        "flashing {} to {}",
+        dictionary_path.display(),
+        device_path.display()
+    );
+
+    let mismatching = mismatching_spans(&mut device, &dictionary).await;
+    let mut bytes_written: u64 = 0;
+    for span in &mismatching {
+        let cd = &dictionary.chunk_descriptors[span.descriptor];
+        let hash = HashSum::from_slice(&cd.checksum[..]);
+        let chunk = store
+            .read_chunk(&hash, cd.source_size as usize)
+            .await
+            .unwrap_or_else(|err| panic!("failed to read chunk {}: {}", hash, err));
+        device
+            .seek(SeekFrom::Start(span.offset))
+            .await
+            .expect("seek device");
+        device.write_all(&chunk).await.expect("write device");
+        bytes_written += chunk.len() as u64;
+    }
+    device.flush().await.expect("flush device");
+    if is_file && size > dictionary.source_total_size {
+        device
+            .set_len(dictionary.source_total_size)
+            .await
+            .expect("truncate file");
+    }
+    device.sync_all().await.expect("sync device");
+    info!(
+        "Successfully flashed {} to {}, wrote {} in {} regions ({} unchanged)",
+        dictionary_path.display(),
+        device_path.display(),
+        size_str(bytes_written),
+        span_ranges(&mismatching, &dictionary).len(),
+        size_str(dictionary.source_total_size - bytes_written)
+    );
+}
+
+// Check that a device holds the image of a dictionary. Returns false and
+// reports where if it doesn't.
+pub async fn verify(dictionary_path: &Path, device_path: &Path) -> bool {
+    let dictionary = read_dictionary(dictionary_path).await;
+    let mut device = File::open(device_path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to open {}: {}", device_path.display(), err));
+    info!(
+        "verifying {} against {}",
+        device_path.display(),
+        dictionary_path.display()
+    );
+    let mismatching = mismatching_spans(&mut device, &dictionary).await;
+    let ranges = span_ranges(&mismatching, &dictionary);
+    match ranges.first() {
+        None => {
+            info!(
+                "{} holds the image of {}",
+                device_path.display(),
+                dictionary_path.display()
+            );
+            true
+        }
+        Some((offset, size)) => {
+            error!(
+                "{} differs from {} in {} ranges ({} in total), first at offset {} ({})",
+                device_path.display(),
+                dictionary_path.display(),
+                ranges.len(),
+                size_str(ranges.iter().map(|(_, size)| size).sum::<u64>()),
+                offset,
+                size_str(*size)
+            );
+            for (offset, size) in &ranges {
+                debug!("mismatch at offset {} ({} bytes)", offset, size);
+            }
+            false
+        }
+    }
+}
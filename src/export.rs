@@ -0,0 +1,284 @@
+use bitar::{chunk_dictionary as dict, Compression, HashSum};
+use blake2::{Blake2b, Digest};
+use log::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::size_str::size_str;
+use crate::store::{read_dictionary, ChunkStore};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+use crate::storedict;
+
+fn archive_chunker_params(params: &storedict::ChunkerParameters) -> dict::ChunkerParameters {
+    dict::ChunkerParameters {
+        chunk_filter_bits: params.chunk_filter_bits,
+        min_chunk_size: params.min_chunk_size,
+        max_chunk_size: params.max_chunk_size,
+        rolling_hash_window_size: params.rolling_hash_window_size,
+        chunk_hash_length: params.chunk_hash_length,
+        chunking_algorithm: params.chunking_algorithm,
+    }
+}
+
+async fn read_chunk(store: &ChunkStore, cd: &storedict::ChunkDescriptor) -> Vec<u8> {
+    let hash = HashSum::from_slice(&cd.checksum[..]);
+    store
+        .read_chunk(&hash, cd.source_size as usize)
+        .await
+        .unwrap_or_else(|err| panic!("failed to read chunk {}: {}", hash, err))
+}
+
+// Build a bita archive from a dictionary and the chunks in its store.
+pub async fn export_archive(
+    dictionary_path: &Path,
+    output: &Path,
+    compression: Compression,
+    force_create: bool,
+    lock_wait: LockWait,
+) {
+    let store_root = dictionary_path.parent().expect("store root");
+    let _lock = StoreLock::acquire(store_root, LockMode::Shared, lock_wait)
+        .await
+        .unwrap_or_else(|| panic!("store {} is locked", store_root.display()));
+    let dictionary = read_dictionary(dictionary_path).await;
+    let store = ChunkStore::for_dictionary(store_root, &dictionary);
+    info!(
+        "exporting {} to archive {} ({} compression)",
+        dictionary_path.display(),
+        output.display(),
+        compression
+    );
+
+    // The header depends on the compressed chunk sizes, so keep the chunk
+    // data aside until the header has been written.
+    let data_path = PathBuf::from(format!("{}.part", output.display()));
+    let mut data_file = File::create(&data_path)
+        .await
+        .expect("create chunk data file");
+    let mut archive_offset: u64 = 0;
+    let mut chunk_descriptors = Vec::with_capacity(dictionary.chunk_descriptors.len());
+    for cd in &dictionary.chunk_descriptors {
+        let chunk = read_chunk(&store, cd).await;
+        let compressed = compression
+            .compress(chunk.clone().into())
+            .expect("compress chunk");
+        // Same as bita, store the chunk uncompressed if compression doesn't
+        // make it smaller. Readers tell by archive size equal to source size.
+        let data = if compressed.len() < chunk.len() {
+            &compressed[..]
+        } else {
+            &chunk[..]
+        };
+        data_file.write_all(data).await.expect("write chunk data");
+        chunk_descriptors.push(dict::ChunkDescriptor {
+            checksum: cd.checksum.clone(),
+            archive_size: data.len() as u32,
+            archive_offset,
+            source_size: cd.source_size,
+        });
+        archive_offset += data.len() as u64;
+    }
+    data_file.flush().await.expect("flush chunk data");
+
+    let header = bitar::header::build(
+        &dict::ChunkDictionary {
+            application_version: crate::PKG_VERSION.to_string(),
+            source_checksum: dictionary.source_checksum.clone(),
+            source_total_size: dictionary.source_total_size,
+            rebuild_order: dictionary.source_order.clone(),
+            chunk_descriptors,
+            chunker_params: Some(archive_chunker_params(
+                dictionary
+                    .chunker_params
+                    .as_ref()
+                    .expect("dictionary has no chunker parameters"),
+            )),
+            chunk_compression: Some(compression.into()),
+        },
+        None,
+    )
+    .expect("build archive header");
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(force_create)
+        .truncate(force_create)
+        .create_new(!force_create)
+        .open(output)
+        .await
+        .expect("open output file");
+    output_file
+        .write_all(&header)
+        .await
+        .expect("write archive header");
+    let mut data_file = File::open(&data_path).await.expect("open chunk data file");
+    tokio::io::copy(&mut data_file, &mut output_file)
+        .await
+        .expect("write chunk data");
+    output_file.flush().await.expect("flush output file");
+    tokio::fs::remove_file(&data_path)
+        .await
+        .expect("remove chunk data file");
+    info!(
+        "Successfully exported {} chunks ({} of {} source) to {}",
+        dictionary.chunk_descriptors.len(),
+        size_str(header.len() as u64 + archive_offset),
+        size_str(dictionary.source_total_size),
+        output.display()
+    );
+}
+
+// Part of the image to export, as offset and length in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Range {
+    pub offset: u64,
+    pub len: u64,
+}
+
+// Where an image is written. Zero chunks are not written to a regular file,
+// leaving holes which makes the file sparse.
+enum Output {
+    Stream(Box<dyn AsyncWrite + Unpin + Send>),
+    SparseFile(File),
+}
+
+impl Output {
+    async fn open(target: &Path, force_create: bool) -> Self {
+        if target == Path::new("-") {
+            return Self::Stream(Box::new(tokio::io::stdout()));
+        }
+        match tokio::fs::metadata(target).await {
+            // Write devices in place
+            Ok(meta) if !meta.is_file() => Self::Stream(Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .open(target)
+                    .await
+                    .unwrap_or_else(|err| panic!("failed to open {}: {}", target.display(), err)),
+            )),
+            _ => Self::SparseFile(
+                OpenOptions::new()
+                    .write(true)
+                    .create(force_create)
+                    .truncate(force_create)
+                    .create_new(!force_create)
+                    .open(target)
+                    .await
+                    .unwrap_or_else(|err| panic!("failed to open {}: {}", target.display(), err)),
+            ),
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8], zeros: bool) -> std::io::Result<()> {
+        match self {
+            Self::SparseFile(file) if zeros => {
+                file.seek(SeekFrom::Current(buf.len() as i64)).await?;
+                Ok(())
+            }
+            Self::SparseFile(file) => file.write_all(buf).await,
+            Self::Stream(writer) => writer.write_all(buf).await,
+        }
+    }
+
+    async fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::SparseFile(file) => {
+                // A hole at the end is not part of the file until its size is set
+                let size = file.seek(SeekFrom::Current(0)).await?;
+                file.flush().await?;
+                file.set_len(size).await
+            }
+            Self::Stream(writer) => writer.flush().await,
+        }
+    }
+}
+
+// Write the image described by a dictionary to a file, device or stdout (-).
+// A full export is verified against the source checksum of the dictionary.
+pub async fn export_image(
+    dictionary_path: &Path,
+    target: &Path,
+    range: Option<Range>,
+    force_create: bool,
+    lock_wait: LockWait,
+) {
+    let store_root = dictionary_path.parent().expect("store root");
+    let _lock = StoreLock::acquire(store_root, LockMode::Shared, lock_wait)
+        .await
+        .unwrap_or_else(|| panic!("store {} is locked", store_root.display()));
+    let dictionary = read_dictionary(dictionary_path).await;
+    let store = ChunkStore::for_dictionary(store_root, &dictionary);
+    let range = range.unwrap_or(Range {
+        offset: 0,
+        len: dictionary.source_total_size,
+    });
+    let range_end = range.offset + range.len;
+    if range_end > dictionary.source_total_size {
+        panic!(
+            "range {}:{} is outside of image ({} bytes)",
+            range.offset, range.len, dictionary.source_total_size
+        );
+    }
+    let full_image = range.offset == 0 && range_end == dictionary.source_total_size;
+    let mut output = Output::open(target, force_create).await;
+    info!(
+        "exporting {} bytes at offset {} of {} to {}",
+        range.len,
+        range.offset,
+        dictionary_path.display(),
+        target.display()
+    );
+
+    let mut hasher = Blake2b::new();
+    let mut offset: u64 = 0;
+    for index in &dictionary.source_order {
+        if offset >= range_end {
+            break;
+        }
+        let cd = &dictionary.chunk_descriptors[*index as usize];
+        let chunk_end = offset + cd.source_size as u64;
+        if chunk_end > range.offset {
+            let chunk = read_chunk(&store, cd).await;
+            let zeros = store.is_zero_chunk(
+                &HashSum::from_slice(&cd.checksum[..]),
+                cd.source_size as usize,
+            );
+            let start = range.offset.saturating_sub(offset) as usize;
+            let end = (std::cmp::min(chunk_end, range_end) - offset) as usize;
+            output
+                .write(&chunk[start..end], zeros)
+                .await
+                .expect("write image");
+            if full_image {
+                hasher.update(&chunk);
+            }
+        }
+        offset = chunk_end;
+    }
+    output.finish().await.expect("flush image");
+
+    if full_image {
+        let checksum = hasher.finalize().to_vec();
+        if checksum != dictionary.source_checksum {
+            panic!(
+                "image checksum mismatch (expected {}, was {})",
+                HashSum::from_slice(&dictionary.source_checksum[..]),
+                HashSum::from_vec(checksum)
+            );
+        }
+        info!(
+            "Successfully exported {} to {}, checksum {}",
+            dictionary_path.display(),
+            target.display(),
+            HashSum::from_vec(checksum)
+        );
+    } else {
+        info!(
+            "Successfully exported {} bytes of {} to {}",
+            range.len,
+            dictionary_path.display(),
+            target.display()
+        );
+    }
+}
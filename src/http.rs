@@ -0,0 +1,111 @@
+use openssl::{pkcs12::Pkcs12, pkey::PKey, stack::Stack, x509::X509};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use std::path::{Path, PathBuf};
+
+// Settings applied to all http requests towards a remote server.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    // Extra headers as (name, value)
+    pub headers: Vec<(String, String)>,
+    // File holding a bearer token to authenticate with
+    pub token_file: Option<PathBuf>,
+    // PEM file with CA certificate(s) to trust in addition to the system ones
+    pub ca_bundle: Option<PathBuf>,
+    // PEM file with client certificate (and chain) for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    // PEM file with the client certificate key, if not in the certificate file
+    pub client_key: Option<PathBuf>,
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err))
+}
+
+// Parse a header given as "Name: value".
+pub fn parse_header(header: &str) -> (String, String) {
+    let mut split = header.splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some(name), Some(value)) if !name.trim().is_empty() => {
+            (name.trim().to_string(), value.trim().to_string())
+        }
+        _ => panic!("invalid http header '{}', expected 'Name: value'", header),
+    }
+}
+
+impl HttpOptions {
+    fn default_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).expect("invalid http header name"),
+                HeaderValue::from_str(value).expect("invalid http header value"),
+            );
+        }
+        if let Some(token_file) = &self.token_file {
+            let token = String::from_utf8(read_file(token_file)).expect("token is not utf-8");
+            let mut value =
+                HeaderValue::from_str(&format!("Bearer {}", token.trim())).expect("invalid token");
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        headers
+    }
+
+    // Client identity from PEM certificate and key, converted into PKCS #12
+    // which is what the native TLS backend accepts.
+    fn identity(cert_file: &Path, key_file: &Path) -> reqwest::Identity {
+        let mut certs = X509::stack_from_pem(&read_file(cert_file))
+            .expect("failed to parse client certificate")
+            .into_iter();
+        let cert = certs.next().expect("no client certificate found");
+        let mut chain = Stack::new().expect("certificate stack");
+        for ca in certs {
+            chain.push(ca).expect("push certificate");
+        }
+        let key =
+            PKey::private_key_from_pem(&read_file(key_file)).expect("failed to parse client key");
+        let mut builder = Pkcs12::builder();
+        builder.ca(chain);
+        let pkcs12 = builder
+            .build("", "ihop", &key, &cert)
+            .expect("build client identity");
+        reqwest::Identity::from_pkcs12_der(&pkcs12.to_der().expect("encode identity"), "")
+            .expect("load client identity")
+    }
+
+    pub fn client(&self) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder().default_headers(self.default_headers());
+        if let Some(ca_bundle) = &self.ca_bundle {
+            for cert in X509::stack_from_pem(&read_file(ca_bundle)).expect("failed to parse CA") {
+                builder = builder.add_root_certificate(
+                    reqwest::Certificate::from_der(&cert.to_der().expect("encode CA"))
+                        .expect("load CA certificate"),
+                );
+            }
+        }
+        if let Some(cert_file) = &self.client_cert {
+            let key_file = self.client_key.as_ref().unwrap_or(cert_file);
+            builder = builder.identity(Self::identity(cert_file, key_file));
+        } else if self.client_key.is_some() {
+            panic!("client key given without a client certificate");
+        }
+        builder.build().expect("build http client")
+    }
+}
+
+// Read a small file, like a manifest, given as URL or path.
+pub async fn fetch(location: &str, client: &reqwest::Client) -> Result<Vec<u8>, String> {
+    match location.parse::<url::Url>() {
+        Ok(url) => {
+            let response = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| err.to_string())?;
+            let body = response.bytes().await.map_err(|err| err.to_string())?;
+            Ok(body.to_vec())
+        }
+        Err(_) => std::fs::read(location).map_err(|err| err.to_string()),
+    }
+}
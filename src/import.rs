@@ -0,0 +1,124 @@
+use bitar::{chunker::Chunker, clone::CloneOutput, HashSum};
+use blake2::{Blake2b, Digest};
+use futures::StreamExt;
+use log::*;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use crate::clone::CloneError;
+use crate::size_str::size_str;
+use crate::store::{build_store_header, ChunkStore};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+use crate::storedict;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Overwrite the output dictionary if it exists
+    pub force_create: bool,
+    // Verify checksum of chunks already in store
+    pub verify_present: bool,
+    pub lock_wait: LockWait,
+    pub chunker_config: bitar::chunker::Config,
+    pub hash_length: usize,
+    // Security version recorded in the dictionary
+    pub security_version: u64,
+}
+
+// Chunk a raw image and add it to the store, giving the same dictionary as
+// a clone of the image compressed by bita using the same chunker config.
+pub async fn import(
+    image: &Path,
+    output: &Path,
+    store_root: &Path,
+    opts: &Options,
+) -> Result<(), CloneError> {
+    let _lock = StoreLock::acquire(store_root, LockMode::Write, opts.lock_wait)
+        .await
+        .ok_or_else(|| CloneError::StoreLocked(store_root.to_path_buf()))?;
+    let mut output_dict = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(opts.force_create)
+        .create_new(!opts.force_create)
+        .open(&output)
+        .await
+        .expect("open output file");
+    let mut image_file = File::open(image).await.expect("failed to open image");
+    info!(
+        "importing {} to {} (chunks at {}/chunks)",
+        image.display(),
+        output.display(),
+        store_root.display()
+    );
+
+    let chunker_params =
+        ChunkStore::chunker_config_to_params(&opts.chunker_config, opts.hash_length as u32);
+    let mut store = ChunkStore::new(store_root).with_zero_chunks(&chunker_params);
+    let mut source_hasher = Blake2b::new();
+    let mut source_size: u64 = 0;
+    let mut chunk_to_index: HashMap<HashSum, usize> = HashMap::new();
+    let mut chunk_descriptors: Vec<storedict::ChunkDescriptor> = Vec::new();
+    let mut source_order: Vec<u32> = Vec::new();
+    let mut chunks_written = 0;
+    let mut bytes_written = 0;
+    let mut chunker = Chunker::new(&opts.chunker_config, &mut image_file);
+    while let Some(result) = chunker.next().await {
+        let (offset, chunk) = result.expect("read image");
+        source_hasher.update(&chunk);
+        source_size += chunk.len() as u64;
+        let hash = HashSum::b2_digest(&chunk, opts.hash_length);
+        let index = match chunk_to_index.get(&hash) {
+            Some(index) => *index,
+            None => {
+                if !store
+                    .chunk_present(opts.verify_present, &hash, chunk.len())
+                    .await
+                {
+                    store
+                        .write_chunk(&hash, &[offset], &chunk)
+                        .await
+                        .expect("failed to write chunk to store");
+                    chunks_written += 1;
+                    bytes_written += chunk.len() as u64;
+                }
+                chunk_descriptors.push(storedict::ChunkDescriptor {
+                    checksum: hash.to_vec(),
+                    source_size: chunk.len() as u32,
+                });
+                chunk_to_index.insert(hash, chunk_descriptors.len() - 1);
+                chunk_descriptors.len() - 1
+            }
+        };
+        source_order.push(index as u32);
+    }
+    info!(
+        "{} chunks ({} unique) in image, wrote {} chunks ({}) to store",
+        source_order.len(),
+        chunk_descriptors.len(),
+        chunks_written,
+        size_str(bytes_written)
+    );
+
+    let dictionary = storedict::StoreDictionary {
+        application_version: crate::PKG_VERSION.to_string(),
+        chunker_params: Some(chunker_params),
+        source_checksum: source_hasher.finalize().to_vec(),
+        source_total_size: source_size,
+        source_order,
+        chunk_descriptors,
+        verity: None,
+        security_version: opts.security_version,
+    };
+    output_dict
+        .write_all(&build_store_header(&dictionary))
+        .await
+        .expect("write output file");
+    output_dict.flush().await.expect("flush output file");
+    info!(
+        "Successfully imported {} to {}",
+        image.display(),
+        output.display()
+    );
+    Ok(())
+}
@@ -1,28 +1,230 @@
+mod bundle;
+mod channel;
 mod chunk_map;
 mod clone;
+mod device;
+mod export;
+mod http;
+mod import;
+mod manifest;
 mod mount;
 mod mount_file;
+mod peer;
+mod progress;
+mod reader;
+mod serve;
 mod size_str;
+mod slot;
+mod store;
+mod store_lock;
+mod verity;
+mod watch;
 
 use clap::{App, Arg, SubCommand};
-use std::path::Path;
+use size_str::size_str;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 pub const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 pub const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const STORE_MAGIC: &[u8; 6] = b"IHOP1\0";
+pub const BUNDLE_MAGIC: &[u8; 6] = b"IHOPB1";
 
 pub mod storedict {
     include!(concat!(env!("OUT_DIR"), "/store_dictionary.rs"));
 }
 
-fn parse_input_config(matches: &clap::ArgMatches<'_>) -> clone::InputArchive {
+pub mod bundledict {
+    include!(concat!(env!("OUT_DIR"), "/bundle.rs"));
+}
+
+fn parse_http_options(matches: &clap::ArgMatches<'_>) -> http::HttpOptions {
+    http::HttpOptions {
+        headers: matches
+            .values_of("http-header")
+            .map(|headers| headers.map(http::parse_header).collect())
+            .unwrap_or_default(),
+        token_file: matches.value_of("http-token-file").map(PathBuf::from),
+        ca_bundle: matches.value_of("http-ca").map(PathBuf::from),
+        client_cert: matches.value_of("http-client-cert").map(PathBuf::from),
+        client_key: matches.value_of("http-client-key").map(PathBuf::from),
+    }
+}
+
+fn parse_progress(matches: &clap::ArgMatches<'_>) -> progress::Progress {
+    match matches.value_of("progress") {
+        Some("json") => {}
+        Some(format) => panic!("unknown progress format {}", format),
+        None => return progress::Progress::none(),
+    }
+    match matches.value_of("progress-fd") {
+        Some(fd) => {
+            use std::os::unix::io::FromRawFd;
+            let fd = fd.parse().expect("failed to parse progress-fd");
+            // The file descriptor is handed to us by the parent process and not
+            // used for anything else.
+            let file = unsafe { std::fs::File::from_raw_fd(fd) };
+            progress::Progress::json(Box::new(file))
+        }
+        None => progress::Progress::json(Box::new(std::io::stdout())),
+    }
+}
+
+fn parse_peers(matches: &clap::ArgMatches<'_>) -> Vec<peer::PeerStore> {
+    // Peers are not sent the credentials of the archive server
+    let client = reqwest::Client::new();
+    matches
+        .values_of("peer")
+        .map(|peers| {
+            peers
+                .map(|peer| peer::PeerStore::new(peer, &client))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_input_config(
+    matches: &clap::ArgMatches<'_>,
+    rate_limit: &reader::RateLimit,
+) -> clone::InputArchive {
     let input = matches.value_of("INPUT").unwrap().to_string();
+    if matches.is_present("from-store") {
+        // Split into store location and dictionary name
+        if matches.is_present("mirror") {
+            panic!("mirrors can only be used with a remote archive");
+        }
+        let input = input.trim_end_matches('/');
+        let (location, name) = match input.rfind('/') {
+            Some(pos) => (&input[..pos], &input[pos + 1..]),
+            None => (".", input),
+        };
+        return clone::InputArchive::Store {
+            store: peer::PeerStore::new(location, &parse_http_options(matches).client()),
+            name: name.to_string(),
+        };
+    }
+    let mut input_archive = archive_input(&input, matches, rate_limit);
+    if let Some(mirrors) = matches.values_of("mirror") {
+        match &mut input_archive {
+            clone::InputArchive::Remote { urls, .. } => {
+                urls.extend(mirrors.map(|mirror| mirror.parse().expect("failed to parse mirror")))
+            }
+            _ => panic!("mirrors can only be used with a remote archive"),
+        }
+    }
+    input_archive
+}
+
+// Input of clone and install, with the location of the archive
+enum ResolvedInput {
+    Archive(Box<clone::InputArchive>, String),
+    // Release of channel already in store
+    InStore(PathBuf),
+}
+
+// Resolve the input of clone and install, a channel into the archive of its
+// current release.
+async fn resolve_input(
+    matches: &clap::ArgMatches<'_>,
+    store_root: &Path,
+    opts: &mut clone::Options,
+    rate_limit: &reader::RateLimit,
+) -> ResolvedInput {
+    let location = matches.value_of("INPUT").unwrap();
+    if !matches.is_present("channel") {
+        return ResolvedInput::Archive(
+            Box::new(parse_input_config(matches, rate_limit)),
+            location.to_string(),
+        );
+    }
+    let pointer = match http::fetch(location, &parse_http_options(matches).client())
+        .await
+        .and_then(|buf| channel::parse_pointer(&buf, location))
+    {
+        Ok(pointer) => pointer,
+        Err(err) => {
+            let err = clone::CloneError::ArchiveUnavailable(format!("{}: {}", location, err));
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    };
+    if let Some(path) = store::find_dictionary(store_root, pointer.source_checksum.slice()) {
+        log::info!(
+            "release {} of {} already in store as {}",
+            pointer.version,
+            location,
+            path.display()
+        );
+        return ResolvedInput::InStore(path);
+    }
+    log::info!(
+        "release {} of {} is {}",
+        pointer.version,
+        location,
+        pointer.url
+    );
+    opts.source_checksum = Some(pointer.source_checksum);
+    if let Some(security_version) = pointer.security_version {
+        opts.security_version = security_version;
+    }
+    ResolvedInput::Archive(
+        Box::new(archive_input(&pointer.url, matches, rate_limit)),
+        pointer.url,
+    )
+}
+
+// Dictionary name to install a release as, and if the release is already in
+// store under that name. A different release already having the name is kept,
+// and the new one named by its checksum instead.
+async fn install_name(
+    store_root: &Path,
+    name: String,
+    input: &clone::InputArchive,
+    opts: &clone::Options,
+) -> Result<(String, bool), clone::CloneError> {
+    if !store_root.join(&name).is_file() {
+        return Ok((name, false));
+    }
+    let source_checksum = clone::source_checksum_of(input).await?;
+    let mut name = name;
+    let mut dictionary = store::read_dictionary(&store_root.join(&name)).await;
+    if dictionary.source_checksum != source_checksum.slice() {
+        log::info!("{} in store is another release", name);
+        name = store::checksum_name(&name, source_checksum.slice());
+        let path = store_root.join(&name);
+        if !path.is_file() {
+            return Ok((name, false));
+        }
+        dictionary = store::read_dictionary(&path).await;
+        if dictionary.source_checksum != source_checksum.slice() {
+            return Err(clone::CloneError::ChecksumMismatch {
+                expected: source_checksum.to_string(),
+                actual: bitar::HashSum::from_slice(&dictionary.source_checksum).to_string(),
+            });
+        }
+    }
+    store::check_security_version(
+        store_root,
+        dictionary.security_version,
+        opts.allow_downgrade,
+    )
+    .map_err(|minimum| clone::CloneError::Downgrade {
+        version: dictionary.security_version,
+        minimum,
+    })?;
+    Ok((name, true))
+}
+
+fn archive_input(
+    input: &str,
+    matches: &clap::ArgMatches<'_>,
+    rate_limit: &reader::RateLimit,
+) -> clone::InputArchive {
     match input.parse::<url::Url>() {
         Ok(url) => {
             // Use as URL
             clone::InputArchive::Remote {
-                url,
+                urls: vec![url],
                 retries: matches
                     .value_of("http-retry-count")
                     .unwrap_or("0")
@@ -37,33 +239,306 @@ fn parse_input_config(matches: &clap::ArgMatches<'_>) -> clone::InputArchive {
                 receive_timeout: matches
                     .value_of("http-timeout")
                     .map(|v| Duration::from_secs(v.parse().expect("failed to parse http-timeout"))),
+                stall_timeout: matches.value_of("http-stall-timeout").map(|v| {
+                    Duration::from_secs(v.parse().expect("failed to parse http-stall-timeout"))
+                }),
+                http: parse_http_options(matches),
+                rate_limit: rate_limit.clone(),
             }
         }
-        Err(_) => {
-            // Use as path
-            clone::InputArchive::Local(input.into())
-        }
+        // Use as path
+        Err(_) => clone::InputArchive::Local(input.into()),
     }
 }
 
-fn parse_size(size_str: &str) -> usize {
+fn parse_clone_options(matches: &clap::ArgMatches<'_>) -> clone::Options {
+    clone::Options {
+        force_create: matches.is_present("force-create"),
+        verify_present: !matches.is_present("naive"),
+        dry_run: matches.is_present("dry-run"),
+        lock_wait: parse_lock_wait(matches),
+        seeds: matches
+            .values_of("seed")
+            .map(|seeds| seeds.map(PathBuf::from).collect())
+            .unwrap_or_default(),
+        peers: parse_peers(matches),
+        // Only taken from the release (channel, manifest or source store)
+        security_version: 0,
+        allow_downgrade: matches.is_present("allow-downgrade"),
+        source_checksum: None,
+    }
+}
+
+fn parse_lock_wait(matches: &clap::ArgMatches<'_>) -> store_lock::LockWait {
+    if let Some(timeout) = matches.value_of("lock-timeout") {
+        store_lock::LockWait::Timeout(Duration::from_secs(
+            timeout.parse().expect("failed to parse lock-timeout"),
+        ))
+    } else if matches.is_present("wait") {
+        store_lock::LockWait::Forever
+    } else {
+        store_lock::LockWait::No
+    }
+}
+
+fn try_parse_size(size_str: &str) -> Option<usize> {
     let size_val: String = size_str.chars().filter(|a| a.is_numeric()).collect();
-    let size_val: usize = size_val.parse().expect("parse");
+    let size_val: usize = size_val.parse().ok()?;
     let size_unit: String = size_str.chars().filter(|a| !a.is_numeric()).collect();
     if size_unit.is_empty() {
-        return size_val;
+        return Some(size_val);
     }
     match size_unit.as_str() {
-        "GiB" => 1024 * 1024 * 1024 * size_val,
-        "MiB" => 1024 * 1024 * size_val,
-        "KiB" => 1024 * size_val,
-        "B" => size_val,
-        _ => panic!("Invalid size unit"),
+        "GiB" => Some(1024 * 1024 * 1024 * size_val),
+        "MiB" => Some(1024 * 1024 * size_val),
+        "KiB" => Some(1024 * size_val),
+        "B" => Some(size_val),
+        _ => None,
+    }
+}
+
+fn parse_size(size_str: &str) -> usize {
+    try_parse_size(size_str).expect("Invalid size")
+}
+
+// Chunker config and hash length, defaults as for bita compress.
+fn parse_chunker_config(matches: &clap::ArgMatches<'_>) -> (bitar::chunker::Config, usize) {
+    let hash_length: usize = matches
+        .value_of("hash-length")
+        .unwrap_or("64")
+        .parse()
+        .expect("failed to parse hash-length");
+    if hash_length == 0 || hash_length > 64 {
+        panic!("hash-length must be in range 1-64");
+    }
+    if let Some(fixed_size) = matches.value_of("fixed-size") {
+        return (
+            bitar::chunker::Config::FixedSize(parse_size(fixed_size)),
+            hash_length,
+        );
+    }
+    let avg_chunk_size = parse_size(matches.value_of("avg-chunk-size").unwrap_or("64KiB"));
+    let min_chunk_size = parse_size(matches.value_of("min-chunk-size").unwrap_or("16KiB"));
+    let max_chunk_size = parse_size(matches.value_of("max-chunk-size").unwrap_or("16MiB"));
+    if min_chunk_size > avg_chunk_size || avg_chunk_size > max_chunk_size {
+        panic!("chunk sizes must be min <= avg <= max");
+    }
+    let filter_config = |window_size: &str| bitar::chunker::FilterConfig {
+        filter_bits: bitar::chunker::FilterBits::from_size(avg_chunk_size as u32),
+        min_chunk_size,
+        max_chunk_size,
+        window_size: parse_size(window_size),
+    };
+    let config = if matches.is_present("rollsum") {
+        bitar::chunker::Config::RollSum(filter_config(
+            matches.value_of("rolling-hash-window").unwrap_or("64B"),
+        ))
+    } else {
+        bitar::chunker::Config::BuzHash(filter_config(
+            matches.value_of("rolling-hash-window").unwrap_or("16B"),
+        ))
+    };
+    (config, hash_length)
+}
+
+fn parse_compression(matches: &clap::ArgMatches<'_>) -> bitar::Compression {
+    let level = matches
+        .value_of("compression-level")
+        .unwrap_or("6")
+        .parse()
+        .expect("failed to parse compression-level");
+    match matches.value_of("compression").unwrap_or("brotli") {
+        "brotli" if (1..=11).contains(&level) => bitar::Compression::Brotli(level),
+        "brotli" => panic!("brotli compression level must be in range 1-11"),
+        "none" => bitar::Compression::None,
+        compression => panic!("unknown compression {}", compression),
+    }
+}
+
+// Parse a range given as "offset:len", eg "1MiB:4KiB".
+fn parse_range(range_str: &str) -> export::Range {
+    let mut split = range_str.splitn(2, ':');
+    match (
+        split.next().and_then(try_parse_size),
+        split.next().and_then(try_parse_size),
+    ) {
+        (Some(offset), Some(len)) => export::Range {
+            offset: offset as u64,
+            len: len as u64,
+        },
+        _ => panic!("invalid range '{}', expected 'offset:len'", range_str),
+    }
+}
+
+// Parse a rate like "200KiB/s", 0 means unlimited.
+fn parse_rate(rate_str: &str) -> Option<Option<u64>> {
+    let rate_str = rate_str.trim();
+    let rate = try_parse_size(rate_str.strip_suffix("/s").unwrap_or(rate_str))? as u64;
+    Some(if rate > 0 { Some(rate) } else { None })
+}
+
+fn read_rate_file(path: &Path) -> Option<Option<u64>> {
+    match std::fs::read_to_string(path) {
+        Ok(rate_str) if rate_str.trim().is_empty() => Some(None),
+        Ok(rate_str) => parse_rate(&rate_str),
+        Err(err) => {
+            log::warn!("failed to read rate from {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+// Re-read the rate limit from file whenever SIGHUP is received.
+fn reload_rate_on_hangup(path: PathBuf, rate_limit: reader::RateLimit) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("listen for SIGHUP");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match read_rate_file(&path) {
+                Some(rate) => {
+                    match rate {
+                        Some(rate) => log::info!("max download rate set to {}/s", size_str(rate)),
+                        None => log::info!("max download rate removed"),
+                    }
+                    rate_limit.set_rate(rate);
+                }
+                None => log::warn!("invalid rate in {}, keeping current", path.display()),
+            }
+        }
+    });
+}
+
+fn parse_rate_limit(matches: &clap::ArgMatches<'_>) -> reader::RateLimit {
+    let mut rate = matches
+        .value_of("max-rate")
+        .map(|rate| parse_rate(rate).expect("failed to parse max-rate"))
+        .unwrap_or(None);
+    if let Some(path) = matches.value_of("max-rate-file") {
+        let path = PathBuf::from(path);
+        if path.exists() {
+            rate = read_rate_file(&path).expect("failed to parse max-rate-file");
+        }
+        let rate_limit = reader::RateLimit::new(rate);
+        reload_rate_on_hangup(path, rate_limit.clone());
+        return rate_limit;
     }
+    reader::RateLimit::new(rate)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let wait_arg = Arg::with_name("wait")
+        .long("wait")
+        .help("Wait for the store lock if held by someone else");
+    let lock_timeout_arg = Arg::with_name("lock-timeout")
+        .long("lock-timeout")
+        .value_name("SECONDS")
+        .help("Wait at most SECONDS for the store lock");
+    let allow_downgrade_arg = Arg::with_name("allow-downgrade")
+        .long("allow-downgrade")
+        .help("Allow a release with lower security version than the store (logged)");
+    let store_arg = Arg::with_name("store")
+        .long("store")
+        .value_name("DIR")
+        .env("IHOP_STORE")
+        .required(true)
+        .help("Store to manage slots of");
+    let clone_args = [
+        Arg::with_name("force-create")
+            .short("f")
+            .long("force-create")
+            .help("Overwrite dictionary file if it exist"),
+        Arg::with_name("naive")
+            .long("naive")
+            .help("Do not verify the checksum of chunks already present"),
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Only report what would be fetched and written, write nothing"),
+        Arg::with_name("mirror")
+            .long("mirror")
+            .value_name("URL")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Mirror of the remote archive to use if fetching from INPUT fails"),
+        Arg::with_name("http-retry-count")
+            .long("http-retry-count")
+            .value_name("COUNT")
+            .help("Retry transfer on failure [default: 0]"),
+        Arg::with_name("http-retry-delay")
+            .long("http-retry-delay")
+            .value_name("SECONDS")
+            .help("Delay retry for some time on transfer failure [default: 0]"),
+        Arg::with_name("http-timeout")
+            .long("http-timeout")
+            .value_name("SECONDS")
+            .help("Fail transfer if unresponsive for some time"),
+        Arg::with_name("http-stall-timeout")
+            .long("http-stall-timeout")
+            .value_name("SECONDS")
+            .help("Switch mirror if no data was received for some time"),
+        Arg::with_name("max-rate")
+            .long("max-rate")
+            .value_name("RATE")
+            .help("Limit download rate of remote archive (eg 200KiB/s)"),
+        Arg::with_name("max-rate-file")
+            .long("max-rate-file")
+            .value_name("FILE")
+            .help("Read download rate limit from FILE, re-read on SIGHUP"),
+        Arg::with_name("http-header")
+            .long("http-header")
+            .value_name("HEADER")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Extra header ('Name: value') to send with http requests"),
+        Arg::with_name("http-token-file")
+            .long("http-token-file")
+            .value_name("FILE")
+            .help("Authenticate http requests using the bearer token in FILE"),
+        Arg::with_name("http-ca")
+            .long("http-ca")
+            .value_name("FILE")
+            .help("Trust the PEM encoded CA certificate(s) in FILE"),
+        Arg::with_name("http-client-cert")
+            .long("http-client-cert")
+            .value_name("FILE")
+            .help("PEM encoded client certificate for TLS authentication"),
+        Arg::with_name("http-client-key")
+            .long("http-client-key")
+            .value_name("FILE")
+            .help("PEM encoded key of the client certificate [default: same file as certificate]"),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("FILE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("File or device to use chunks from before fetching from archive"),
+        Arg::with_name("from-store").long("from-store").help(
+            "Input is a dictionary in another store (URL or directory) instead of an archive",
+        ),
+        Arg::with_name("channel")
+            .long("channel")
+            .conflicts_with("from-store")
+            .help("Input is a channel (URL or file) pointing at the current release archive"),
+        Arg::with_name("peer")
+            .long("peer")
+            .value_name("STORE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Store (URL or directory) to fetch chunks from before fetching from archive"),
+        Arg::with_name("progress")
+            .long("progress")
+            .value_name("FORMAT")
+            .possible_values(&["json"])
+            .help("Report progress events as JSON lines on stdout"),
+        Arg::with_name("progress-fd")
+            .long("progress-fd")
+            .value_name("FD")
+            .requires("progress")
+            .help("Write progress to file descriptor FD instead of stdout"),
+        allow_downgrade_arg.clone(),
+        wait_arg.clone(),
+        lock_timeout_arg.clone(),
+    ];
     let matches = App::new(PKG_NAME)
         .version(PKG_VERSION)
         .arg(
@@ -93,15 +568,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .long("block-size")
                         .value_name("SIZE")
                         .help("Set the chunk data compression level (0-9) [default: 6]"),
-                ),
+                )
+                .arg(
+                    Arg::with_name("max-attempts")
+                        .long("max-attempts")
+                        .value_name("COUNT")
+                        .help("Fall back to the previous release if the current slot is not confirmed after COUNT attempts [default: 3]"),
+                )
+                .arg(
+                    Arg::with_name("verity")
+                        .long("verity")
+                        .value_name("NAME")
+                        .help("Open dm-verity device NAME on top, using the root hash stored in dictionary"),
+                )
+                .arg(allow_downgrade_arg.clone())
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
         )
         .subcommand(
-            SubCommand::with_name("clone")
-                .about("Clone a bita archive to a store.")
+            SubCommand::with_name("import")
+                .about("Chunk a raw image into a store.")
                 .arg(
-                    Arg::with_name("INPUT")
-                        .value_name("INPUT")
-                        .help("Input file (can be a local archive or a URL)")
+                    Arg::with_name("IMAGE")
+                        .value_name("IMAGE")
+                        .help("Image file to import")
                         .required(true),
                 )
                 .arg(
@@ -120,8 +610,430 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Arg::with_name("naive")
                         .long("naive")
                         .help("Do not verify the checksum of chunks already present"),
+                )
+                .arg(
+                    Arg::with_name("avg-chunk-size")
+                        .long("avg-chunk-size")
+                        .value_name("SIZE")
+                        .help("Indication of target chunk size [default: 64KiB]"),
+                )
+                .arg(
+                    Arg::with_name("min-chunk-size")
+                        .long("min-chunk-size")
+                        .value_name("SIZE")
+                        .help("Set minimal size of chunks [default: 16KiB]"),
+                )
+                .arg(
+                    Arg::with_name("max-chunk-size")
+                        .long("max-chunk-size")
+                        .value_name("SIZE")
+                        .help("Set maximal size of chunks [default: 16MiB]"),
+                )
+                .arg(
+                    Arg::with_name("rolling-hash-window")
+                        .long("rolling-hash-window")
+                        .value_name("SIZE")
+                        .help("Size of the rolling hash window [default: 16B for BuzHash, 64B for RollSum]"),
+                )
+                .arg(
+                    Arg::with_name("rollsum")
+                        .long("rollsum")
+                        .conflicts_with("fixed-size")
+                        .help("Use RollSum instead of BuzHash to find chunk boundaries"),
+                )
+                .arg(
+                    Arg::with_name("fixed-size")
+                        .long("fixed-size")
+                        .value_name("SIZE")
+                        .conflicts_with_all(&["avg-chunk-size", "min-chunk-size", "max-chunk-size", "rolling-hash-window"])
+                        .help("Use fixed size chunks instead of content defined"),
+                )
+                .arg(
+                    Arg::with_name("hash-length")
+                        .long("hash-length")
+                        .value_name("LENGTH")
+                        .help("Truncate chunk hashes to LENGTH bytes [default: 64]"),
+                )
+                .arg(
+                    Arg::with_name("security-version")
+                        .long("security-version")
+                        .value_name("VERSION")
+                        .help("Security version of the release to record in the dictionary [default: 0]"),
+                )
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("flash")
+                .about("Write the image of a dictionary to a device, only writing what differs.")
+                .arg(
+                    Arg::with_name("DICTIONARY")
+                        .value_name("DICTIONARY")
+                        .help("Dictionary of the image to flash")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DEVICE")
+                        .value_name("DEVICE")
+                        .help("Block device (or file) to write image to")
+                        .required(true),
+                )
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-device")
+                .about("Check that a device (or file) holds the image of a dictionary.")
+                .arg(
+                    Arg::with_name("DICTIONARY")
+                        .value_name("DICTIONARY")
+                        .help("Dictionary of the expected image")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DEVICE")
+                        .value_name("DEVICE")
+                        .help("Block device or file to check")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verity")
+                .about("Generate a dm-verity hash tree of the image of a dictionary.")
+                .arg(
+                    Arg::with_name("DICTIONARY")
+                        .value_name("DICTIONARY")
+                        .help("Dictionary of the image")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("hash-tree")
+                        .long("hash-tree")
+                        .value_name("FILE")
+                        .help("Where to write the hash tree [default: DICTIONARY.verity]"),
+                )
+                .arg(
+                    Arg::with_name("data-block-size")
+                        .long("data-block-size")
+                        .value_name("SIZE")
+                        .help("Block size of the data device [default: 4096]"),
+                )
+                .arg(
+                    Arg::with_name("hash-block-size")
+                        .long("hash-block-size")
+                        .value_name("SIZE")
+                        .help("Block size of the hash device [default: 4096]"),
+                )
+                .arg(
+                    Arg::with_name("salt")
+                        .long("salt")
+                        .value_name("HEX")
+                        .help("Salt to use [default: random 32 bytes]"),
+                )
+                .arg(
+                    Arg::with_name("store-root-hash")
+                        .long("store-root-hash")
+                        .help("Store root hash and hash tree location in the dictionary"),
+                )
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the image of a dictionary in a store to a file, device or stdout.")
+                .arg(
+                    Arg::with_name("DICTIONARY")
+                        .value_name("DICTIONARY")
+                        .help("Dictionary of the image to export")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .value_name("TARGET")
+                        .help("File or device to write image to, - for stdout")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("force-create")
+                        .short("f")
+                        .long("force-create")
+                        .help("Overwrite target file if it exist"),
+                )
+                .arg(
+                    Arg::with_name("range")
+                        .long("range")
+                        .value_name("OFFSET:LEN")
+                        .help("Only export LEN bytes starting at OFFSET of the image"),
+                )
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("export-archive")
+                .about("Build a bita archive from a dictionary in a store.")
+                .arg(
+                    Arg::with_name("DICTIONARY")
+                        .value_name("DICTIONARY")
+                        .help("Dictionary of the image to export")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .value_name("OUTPUT")
+                        .help("Archive file to create")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("force-create")
+                        .short("f")
+                        .long("force-create")
+                        .help("Overwrite archive file if it exist"),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .long("compression")
+                        .value_name("TYPE")
+                        .possible_values(&["brotli", "none"])
+                        .help("Set the chunk data compression type [default: brotli]"),
+                )
+                .arg(
+                    Arg::with_name("compression-level")
+                        .long("compression-level")
+                        .value_name("LEVEL")
+                        .help("Set the chunk data compression level [default: 6]"),
+                )
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("bundle")
+                .about("Pack releases into a single file for offline delivery.")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a bundle of dictionaries and their chunks.")
+                        .arg(
+                            Arg::with_name("DICTIONARY")
+                                .value_name("DICTIONARY")
+                                .help("Dictionaries to include")
+                                .multiple(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("OUTPUT")
+                                .value_name("OUTPUT")
+                                .help("Bundle file to create")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("force-create")
+                                .short("f")
+                                .long("force-create")
+                                .help("Overwrite bundle file if it exist"),
+                        )
+                        .arg(wait_arg.clone())
+                        .arg(lock_timeout_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("delta")
+                        .about("Create a bundle of a dictionary and the chunks not in a base dictionary.")
+                        .arg(
+                            Arg::with_name("BASE")
+                                .value_name("BASE")
+                                .help("Dictionary of the release installed on target")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("DICTIONARY")
+                                .value_name("DICTIONARY")
+                                .help("Dictionary of the new release")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("OUTPUT")
+                                .value_name("OUTPUT")
+                                .help("Bundle file to create")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("force-create")
+                                .short("f")
+                                .long("force-create")
+                                .help("Overwrite bundle file if it exist"),
+                        )
+                        .arg(wait_arg.clone())
+                        .arg(lock_timeout_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("apply")
+                        .about("Install the dictionaries and missing chunks of a bundle in a store.")
+                        .arg(
+                            Arg::with_name("BUNDLE")
+                                .value_name("BUNDLE")
+                                .help("Bundle to apply")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("STORE")
+                                .value_name("STORE")
+                                .help("Store directory to install to")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("force-create")
+                                .short("f")
+                                .long("force-create")
+                                .help("Overwrite dictionaries in store if they exist"),
+                        )
+                        .arg(
+                            Arg::with_name("naive")
+                                .long("naive")
+                                .help("Do not verify the checksum of chunks already present"),
+                        )
+                        .arg(allow_downgrade_arg.clone())
+                        .arg(wait_arg.clone())
+                        .arg(lock_timeout_arg.clone()),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("serve-http")
+                .about("Serve the dictionaries and chunks of a store over http.")
+                .arg(
+                    Arg::with_name("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("Store to serve")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .help("Address to listen on [default: 0.0.0.0:8080]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clone")
+                .about("Clone a bita archive to a store.")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .value_name("INPUT")
+                        .help("Input file (can be a local archive or a URL)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .value_name("OUTPUT")
+                        .help("Where to store chunks and dictionary")
+                        .required(true),
+                )
+                .args(&clone_args),
+        )
+        .subcommand(
+            SubCommand::with_name("clone-manifest")
+                .about("Clone all images of a release manifest to a store.")
+                .arg(
+                    Arg::with_name("MANIFEST")
+                        .value_name("MANIFEST")
+                        .help("Release manifest (can be a local file or a URL)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .value_name("OUTPUT")
+                        .help("Store and release name, images are stored as OUTPUT.<image>")
+                        .required(true),
+                )
+                .args(&clone_args),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Poll a channel or archive for new releases and clone them to a store.")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .value_name("INPUT")
+                        .help("Channel (with --channel) or archive to watch, a URL or a local file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("STORE")
+                        .value_name("STORE")
+                        .help("Store to clone new releases to")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .help("Time between polls [default: 3600]"),
+                )
+                .arg(
+                    Arg::with_name("jitter")
+                        .long("jitter")
+                        .value_name("SECONDS")
+                        .help("Randomize the time between polls by up to SECONDS [default: interval / 10]"),
+                )
+                .arg(
+                    Arg::with_name("hook")
+                        .long("hook")
+                        .value_name("COMMAND")
+                        .help("Command to run (by sh) when a new release is in store"),
+                )
+                .args(&clone_args),
+        )
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("Clone a bita archive to a store and point a slot at it.")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .value_name("INPUT")
+                        .help("Input file (can be a local archive or a URL)")
+                        .required(true),
+                )
+                .arg(store_arg.clone())
+                .arg(
+                    Arg::with_name("slot")
+                        .long("slot")
+                        .value_name("SLOT")
+                        .help("Slot to point at the release [default: next]"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .value_name("NAME")
+                        .help("Dictionary name of the release [default: file name of INPUT]"),
+                )
+                .args(&clone_args),
+        )
+        .subcommand(
+            SubCommand::with_name("switch")
+                .about("Make the release of a slot current, keeping the current one as previous.")
+                .arg(
+                    Arg::with_name("SLOT")
+                        .value_name("SLOT")
+                        .help("Slot to switch to")
+                        .required(true),
+                )
+                .arg(store_arg.clone())
+                .arg(allow_downgrade_arg.clone())
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("confirm")
+                .about("Mark the current release as good.")
+                .arg(store_arg.clone())
+                .arg(wait_arg.clone())
+                .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Switch back to the previous release.")
+                .arg(store_arg)
+                .arg(allow_downgrade_arg)
+                .arg(wait_arg)
+                .arg(lock_timeout_arg),
+        )
         .get_matches();
 
     // Init logger
@@ -138,24 +1050,367 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Handle mount subcommand
     if let Some(matches) = matches.subcommand_matches("mount") {
-        let backend = Path::new(matches.value_of("BACKEND").unwrap());
+        let mut backend = PathBuf::from(matches.value_of("BACKEND").unwrap());
         let nbd_dev = Path::new(matches.value_of("NBD").unwrap());
         let block_size = parse_size(matches.value_of("avg-chunk-size").unwrap_or("512B")) as u32;
-        mount::mount(backend, nbd_dev, block_size).await
+        if slot::is_current(&backend) {
+            // Count the attempt, possibly falling back to the previous release
+            let store_root = store::store_root_of(&backend);
+            let max_attempts = matches
+                .value_of("max-attempts")
+                .unwrap_or("3")
+                .parse()
+                .expect("failed to parse max-attempts");
+            backend = slot::boot(&store_root, max_attempts, parse_lock_wait(matches))
+                .await
+                .unwrap_or_else(|err| {
+                    log::error!("{}", err);
+                    std::process::exit(err.exit_code());
+                });
+        }
+        if let Err(err) = mount::mount(
+            &backend,
+            nbd_dev,
+            block_size,
+            parse_lock_wait(matches),
+            matches.value_of("verity"),
+            matches.is_present("allow-downgrade"),
+        )
+        .await
+        {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    // Handle import subcommand
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let image = Path::new(matches.value_of("IMAGE").unwrap());
+        let output = Path::new(matches.value_of("OUTPUT").unwrap());
+        let store_root = &store::store_root_of(output);
+        let (chunker_config, hash_length) = parse_chunker_config(matches);
+        let opts = import::Options {
+            force_create: matches.is_present("force-create"),
+            verify_present: !matches.is_present("naive"),
+            lock_wait: parse_lock_wait(matches),
+            chunker_config,
+            hash_length,
+            security_version: matches
+                .value_of("security-version")
+                .map(|v| v.parse().expect("failed to parse security-version"))
+                .unwrap_or(0),
+        };
+        if let Err(err) = import::import(image, output, store_root, &opts).await {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    // Handle flash subcommand
+    if let Some(matches) = matches.subcommand_matches("flash") {
+        device::flash(
+            Path::new(matches.value_of("DICTIONARY").unwrap()),
+            Path::new(matches.value_of("DEVICE").unwrap()),
+            parse_lock_wait(matches),
+        )
+        .await;
+    }
+    // Handle verify-device subcommand
+    if let Some(matches) = matches.subcommand_matches("verify-device") {
+        if !device::verify(
+            Path::new(matches.value_of("DICTIONARY").unwrap()),
+            Path::new(matches.value_of("DEVICE").unwrap()),
+        )
+        .await
+        {
+            std::process::exit(1);
+        }
+    }
+    // Handle verity subcommand
+    if let Some(matches) = matches.subcommand_matches("verity") {
+        let dictionary = Path::new(matches.value_of("DICTIONARY").unwrap());
+        let hash_tree = matches
+            .value_of("hash-tree")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(format!("{}.verity", dictionary.display())));
+        let block_size = |name| {
+            let size = parse_size(matches.value_of(name).unwrap_or("4096"));
+            if !size.is_power_of_two() || size < 512 {
+                panic!("{} must be a power of two and at least 512", name);
+            }
+            size
+        };
+        let opts = verity::Options {
+            data_block_size: block_size("data-block-size"),
+            hash_block_size: block_size("hash-block-size"),
+            salt: matches
+                .value_of("salt")
+                .map(|salt| match verity::parse_hex(salt) {
+                    Some(salt) if salt.len() <= 256 => salt,
+                    _ => panic!("invalid salt '{}'", salt),
+                }),
+            store_root_hash: matches.is_present("store-root-hash"),
+            lock_wait: parse_lock_wait(matches),
+        };
+        verity::verity(dictionary, &hash_tree, &opts).await;
+    }
+    // Handle export subcommand
+    if let Some(matches) = matches.subcommand_matches("export") {
+        export::export_image(
+            Path::new(matches.value_of("DICTIONARY").unwrap()),
+            Path::new(matches.value_of("TARGET").unwrap()),
+            matches.value_of("range").map(parse_range),
+            matches.is_present("force-create"),
+            parse_lock_wait(matches),
+        )
+        .await;
+    }
+    // Handle export-archive subcommand
+    if let Some(matches) = matches.subcommand_matches("export-archive") {
+        export::export_archive(
+            Path::new(matches.value_of("DICTIONARY").unwrap()),
+            Path::new(matches.value_of("OUTPUT").unwrap()),
+            parse_compression(matches),
+            matches.is_present("force-create"),
+            parse_lock_wait(matches),
+        )
+        .await;
+    }
+    // Handle bundle subcommands
+    if let Some(matches) = matches.subcommand_matches("bundle") {
+        let result = if let Some(matches) = matches.subcommand_matches("create") {
+            let paths: Vec<PathBuf> = matches
+                .values_of("DICTIONARY")
+                .unwrap()
+                .map(PathBuf::from)
+                .collect();
+            bundle::create(
+                &paths,
+                Path::new(matches.value_of("OUTPUT").unwrap()),
+                matches.is_present("force-create"),
+                parse_lock_wait(matches),
+            )
+            .await
+        } else if let Some(matches) = matches.subcommand_matches("delta") {
+            bundle::delta(
+                Path::new(matches.value_of("BASE").unwrap()),
+                Path::new(matches.value_of("DICTIONARY").unwrap()),
+                Path::new(matches.value_of("OUTPUT").unwrap()),
+                matches.is_present("force-create"),
+                parse_lock_wait(matches),
+            )
+            .await
+        } else if let Some(matches) = matches.subcommand_matches("apply") {
+            bundle::apply(
+                Path::new(matches.value_of("BUNDLE").unwrap()),
+                Path::new(matches.value_of("STORE").unwrap()),
+                matches.is_present("force-create"),
+                !matches.is_present("naive"),
+                matches.is_present("allow-downgrade"),
+                parse_lock_wait(matches),
+            )
+            .await
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    // Handle serve-http subcommand
+    if let Some(matches) = matches.subcommand_matches("serve-http") {
+        serve::serve(
+            Path::new(matches.value_of("store").unwrap()),
+            matches
+                .value_of("listen")
+                .unwrap_or("0.0.0.0:8080")
+                .parse()
+                .expect("failed to parse listen address"),
+        )
+        .await;
     }
     // Handle clone subcommand
     if let Some(matches) = matches.subcommand_matches("clone") {
         let output = Path::new(matches.value_of("OUTPUT").unwrap());
-        let store_root = output.parent().unwrap_or_else(|| Path::new("./"));
-        let input_archive = parse_input_config(&matches);
-        clone::clone(
-            input_archive,
-            output,
+        let store_root = &store::store_root_of(output);
+        let mut opts = parse_clone_options(matches);
+        let input_archive =
+            match resolve_input(matches, store_root, &mut opts, &parse_rate_limit(matches)).await {
+                ResolvedInput::Archive(input_archive, _) => *input_archive,
+                ResolvedInput::InStore(_) => return Ok(()),
+            };
+        let progress = parse_progress(matches);
+        if let Err(err) = clone::clone(input_archive, output, store_root, &opts, &progress).await {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    // Handle clone-manifest subcommand
+    if let Some(matches) = matches.subcommand_matches("clone-manifest") {
+        let location = matches.value_of("MANIFEST").unwrap();
+        let output = Path::new(matches.value_of("OUTPUT").unwrap());
+        let store_root = &store::store_root_of(output);
+        let manifest = match http::fetch(location, &parse_http_options(matches).client()).await {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                let err = clone::CloneError::ArchiveUnavailable(format!("{}: {}", location, err));
+                log::error!("{}", err);
+                std::process::exit(err.exit_code());
+            }
+        };
+        let rate_limit = parse_rate_limit(matches);
+        let images = manifest::parse_manifest(&manifest, location)
+            .into_iter()
+            .map(|image| {
+                let input = archive_input(&image.location, matches, &rate_limit);
+                (image, input)
+            })
+            .collect();
+        let opts = parse_clone_options(matches);
+        let progress = parse_progress(matches);
+        if let Err(err) =
+            manifest::clone_manifest(images, output, store_root, &opts, &progress).await
+        {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    // Handle watch subcommand
+    if let Some(matches) = matches.subcommand_matches("watch") {
+        if matches.is_present("dry-run") || matches.is_present("from-store") {
+            panic!("watch can't be combined with --dry-run or --from-store");
+        }
+        let location = matches.value_of("INPUT").unwrap();
+        let interval = Duration::from_secs(
+            matches
+                .value_of("interval")
+                .map(|v| v.parse().expect("failed to parse interval"))
+                .unwrap_or(3600),
+        );
+        let opts = watch::Options {
+            interval,
+            jitter: matches
+                .value_of("jitter")
+                .map(|v| Duration::from_secs(v.parse().expect("failed to parse jitter")))
+                .unwrap_or(interval / 10),
+            channel: matches.is_present("channel"),
+            hook: matches.value_of("hook").map(String::from),
+            clone: parse_clone_options(matches),
+        };
+        let progress = parse_progress(matches);
+        // Shared by the clones of all polls, to listen for SIGHUP once
+        let rate_limit = parse_rate_limit(matches);
+        watch::watch(
+            location,
+            Path::new(matches.value_of("STORE").unwrap()),
+            parse_http_options(matches).client(),
+            &opts,
+            |input| {
+                if input == location {
+                    parse_input_config(matches, &rate_limit)
+                } else {
+                    archive_input(input, matches, &rate_limit)
+                }
+            },
+            &progress,
+        )
+        .await;
+    }
+    // Handle install subcommand
+    if let Some(matches) = matches.subcommand_matches("install") {
+        let store_root = Path::new(matches.value_of("store").unwrap());
+        let slot = matches.value_of("slot").unwrap_or("next");
+        let mut opts = parse_clone_options(matches);
+        let name = match resolve_input(matches, store_root, &mut opts, &parse_rate_limit(matches))
+            .await
+        {
+            ResolvedInput::Archive(input_archive, location) => {
+                let name = match matches.value_of("name") {
+                    Some(name) => name.to_string(),
+                    None => {
+                        // Release name from the archive file name
+                        let location = location.trim_end_matches('/');
+                        let file_name = location.rsplit('/').next().unwrap();
+                        file_name.trim_end_matches(".cba").to_string()
+                    }
+                };
+                // Check names before cloning anything to the store
+                if let Err(err) = slot::check_names(store_root, slot, &name) {
+                    log::error!("{}", err);
+                    std::process::exit(err.exit_code());
+                }
+                let progress = parse_progress(matches);
+                let (name, in_store) = if opts.force_create {
+                    (name, false)
+                } else {
+                    match install_name(store_root, name, &input_archive, &opts).await {
+                        Ok(install) => install,
+                        Err(err) => {
+                            log::error!("{}", err);
+                            progress.error(&err.to_string(), err.exit_code());
+                            std::process::exit(err.exit_code());
+                        }
+                    }
+                };
+                // Check again if named by checksum
+                if let Err(err) = slot::check_names(store_root, slot, &name) {
+                    log::error!("{}", err);
+                    std::process::exit(err.exit_code());
+                }
+                let output = store_root.join(&name);
+                if in_store {
+                    log::info!("{} already in store", output.display());
+                } else if let Err(err) =
+                    clone::clone(*input_archive, &output, store_root, &opts, &progress).await
+                {
+                    log::error!("{}", err);
+                    std::process::exit(err.exit_code());
+                }
+                name
+            }
+            ResolvedInput::InStore(path) => path.file_name().unwrap().to_string_lossy().to_string(),
+        };
+        if !opts.dry_run {
+            if let Err(err) = slot::set(store_root, slot, &name, opts.lock_wait).await {
+                log::error!("{}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
+    }
+    // Handle switch, confirm and rollback subcommands
+    if let Some(matches) = matches.subcommand_matches("switch") {
+        let store_root = Path::new(matches.value_of("store").unwrap());
+        let slot = matches.value_of("SLOT").unwrap();
+        if let Err(err) = slot::switch(
             store_root,
-            matches.is_present("force-create"),
-            !matches.is_present("naive"),
+            slot,
+            matches.is_present("allow-downgrade"),
+            parse_lock_wait(matches),
         )
         .await
+        {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("confirm") {
+        let store_root = Path::new(matches.value_of("store").unwrap());
+        if let Err(err) = slot::confirm(store_root, parse_lock_wait(matches)).await {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("rollback") {
+        let store_root = Path::new(matches.value_of("store").unwrap());
+        if let Err(err) = slot::rollback(
+            store_root,
+            matches.is_present("allow-downgrade"),
+            parse_lock_wait(matches),
+        )
+        .await
+        {
+            log::error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
     }
     Ok(())
 }
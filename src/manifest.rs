@@ -0,0 +1,178 @@
+use bitar::HashSum;
+use log::*;
+use std::path::{Path, PathBuf};
+
+use crate::clone::{self, CloneError, InputArchive, Options};
+use crate::progress::Progress;
+use crate::store_lock::{LockMode, StoreLock};
+use crate::verity::parse_hex;
+
+// Image of a multi-image release, as listed in a manifest:
+//
+// {"images": [{"name": "rootfs", "url": "rootfs.cba", "source_checksum": "<hex>"}, ...]}
+//
+// Relative image URLs are resolved against the manifest location. The
+// security version of the release is given by an optional security_version,
+// of the manifest or of an image.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub name: String,
+    pub location: String,
+    pub source_checksum: HashSum,
+    pub security_version: u64,
+}
+
+// Resolve a location relative to the manifest (or channel) location.
+pub fn resolve(base: &str, location: &str) -> String {
+    if location.parse::<url::Url>().is_ok() {
+        return location.to_string();
+    }
+    match base.parse::<url::Url>() {
+        Ok(base) => base
+            .join(location)
+            .unwrap_or_else(|err| panic!("invalid image url {}: {}", location, err))
+            .to_string(),
+        Err(_) => Path::new(base)
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(location)
+            .display()
+            .to_string(),
+    }
+}
+
+pub fn parse_manifest(buf: &[u8], base: &str) -> Vec<Image> {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(buf).unwrap_or_else(|err| panic!("invalid manifest: {}", err));
+    let images = manifest["images"]
+        .as_array()
+        .unwrap_or_else(|| panic!("manifest has no images"));
+    let images: Vec<Image> = images
+        .iter()
+        .map(|image| {
+            let field = |name: &str| {
+                image[name]
+                    .as_str()
+                    .unwrap_or_else(|| panic!("manifest image is missing {}", name))
+            };
+            let name = field("name");
+            if name.is_empty() || name.starts_with('.') || name.contains('/') {
+                panic!("invalid image name {:?} in manifest", name);
+            }
+            Image {
+                name: name.to_string(),
+                location: resolve(base, field("url")),
+                source_checksum: HashSum::from_vec(
+                    parse_hex(field("source_checksum"))
+                        .unwrap_or_else(|| panic!("invalid source checksum of {}", name)),
+                ),
+                security_version: image["security_version"]
+                    .as_u64()
+                    .or_else(|| manifest["security_version"].as_u64())
+                    .unwrap_or(0),
+            }
+        })
+        .collect();
+    for (index, image) in images.iter().enumerate() {
+        if images[..index].iter().any(|other| other.name == image.name) {
+            panic!("multiple images named {} in manifest", image.name);
+        }
+    }
+    images
+}
+
+fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// Clone all images of a release to the store, as dictionaries named
+// <release>.<image>. The dictionaries are cloned under temporary names and
+// published only when every image has been cloned.
+pub async fn clone_manifest(
+    images: Vec<(Image, InputArchive)>,
+    release: &Path,
+    store_root: &Path,
+    opts: &Options,
+    progress: &Progress,
+) -> Result<(), CloneError> {
+    let release_name = release
+        .file_name()
+        .unwrap_or_else(|| panic!("invalid release path {}", release.display()))
+        .to_string_lossy()
+        .to_string();
+    let targets: Vec<(PathBuf, PathBuf)> = images
+        .iter()
+        .map(|(image, _)| {
+            (
+                store_root.join(format!(".{}.{}.part", release_name, image.name)),
+                store_root.join(format!("{}.{}", release_name, image.name)),
+            )
+        })
+        .collect();
+    let check_targets = || match targets.iter().find(|(_, path)| path.exists()) {
+        Some((_, path)) if !opts.force_create => Err(CloneError::DictionaryExists(path.clone())),
+        _ => Ok(()),
+    };
+    check_targets()?;
+
+    let mut staged: Vec<PathBuf> = Vec::new();
+    for ((image, input), (staged_path, _)) in images.into_iter().zip(&targets) {
+        info!("cloning image {} of {}", image.name, release_name);
+        let image_opts = Options {
+            // Removed on failure here, along with the other images
+            force_create: true,
+            source_checksum: Some(image.source_checksum),
+            security_version: image.security_version,
+            ..opts.clone()
+        };
+        if !opts.dry_run {
+            let _ = std::fs::remove_file(staged_path);
+            staged.push(staged_path.clone());
+        }
+        if let Err(err) = clone::clone(input, staged_path, store_root, &image_opts, progress).await
+        {
+            error!("failed to clone image {}", image.name);
+            remove_files(&staged);
+            return Err(err);
+        }
+    }
+    if opts.dry_run {
+        return Ok(());
+    }
+
+    // Publish all dictionaries of the release, or none of them
+    let _lock = match StoreLock::acquire(store_root, LockMode::Write, opts.lock_wait).await {
+        Some(lock) => lock,
+        None => {
+            remove_files(&staged);
+            return Err(CloneError::StoreLocked(store_root.to_path_buf()));
+        }
+    };
+    if let Err(err) = check_targets() {
+        remove_files(&staged);
+        return Err(err);
+    }
+    for (index, (staged_path, path)) in targets.iter().enumerate() {
+        if let Err(err) = std::fs::rename(staged_path, path) {
+            error!("failed to publish image of {}", release_name);
+            let published: Vec<PathBuf> = targets[..index]
+                .iter()
+                .map(|(_, path)| path.clone())
+                .collect();
+            remove_files(&published);
+            remove_files(&staged);
+            return Err(CloneError::PublishFailed(path.clone(), err.to_string()));
+        }
+    }
+    std::fs::File::open(store_root)
+        .and_then(|dir| dir.sync_all())
+        .expect("sync store");
+    info!(
+        "Successfully published {} images of {}",
+        targets.len(),
+        release_name
+    );
+    Ok(())
+}
@@ -1,24 +1,28 @@
 use async_trait::async_trait;
 use bitar::HashSum;
-use blake2::{Blake2b, Digest};
 use log::*;
 use nbd_async::BlockDevice;
-use std::convert::TryInto;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{io, io::SeekFrom};
 use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::{
     chunk_map::{ChunkMap, ChunkOffsetSize},
-    clone::chunk_path_from_hash,
+    clone::CloneError,
     mount_file,
+    store::{
+        check_security_version, chunk_path_from_hash, read_dictionary, store_root_of, ChunkStore,
+    },
+    store_lock::{LockMode, LockWait, StoreLock},
 };
 
 struct IhopBackedDevice {
     root_path: PathBuf,
     block_size: u32,
     block_count: u64,
-    chunk_location_map: ChunkMap<PathBuf>,
+    // Chunk file by location, None for chunks of only zeros
+    chunk_location_map: ChunkMap<Option<PathBuf>>,
 }
 
 #[async_trait(?Send)]
@@ -28,18 +32,30 @@ impl BlockDevice for IhopBackedDevice {
         let mut locations = self
             .chunk_location_map
             .iter_overlapping(ChunkOffsetSize::new(offset, buf.len()))
-            .collect::<Vec<(&ChunkOffsetSize, &PathBuf)>>();
+            .collect::<Vec<(&ChunkOffsetSize, &Option<PathBuf>)>>();
         locations.sort_by(|(loca, _), (locb, _)| loca.offset.partial_cmp(&locb.offset).unwrap());
         for (location, path) in locations {
-            let mut chunk_file = File::open(self.root_path.join(path))
-                .await
-                .expect("open chunk file");
-
             let offset_in_file = offset - location.offset;
             let read_from_file = std::cmp::min(
                 buf.len() - buf_offset,
                 location.size - offset_in_file as usize,
             );
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    // Zero chunk, no file in store
+                    buf[buf_offset..buf_offset + read_from_file]
+                        .iter_mut()
+                        .for_each(|b| *b = 0);
+                    buf_offset += read_from_file;
+                    offset += read_from_file as u64;
+                    continue;
+                }
+            };
+            let mut chunk_file = File::open(self.root_path.join(path))
+                .await
+                .expect("open chunk file");
+
             debug!(
                 "requested offset: {} (size {}), chunk start: {} (size: {}), seek to {}",
                 offset,
@@ -72,11 +88,16 @@ fn make_device(
     block_size: u32,
 ) -> IhopBackedDevice {
     let mut offset: u64 = 0;
-    let mut chunk_location_map: ChunkMap<PathBuf> = ChunkMap::new();
+    let mut chunk_location_map: ChunkMap<Option<PathBuf>> = ChunkMap::new();
+    let store = ChunkStore::for_dictionary(root_path, dictionary);
     for index in &dictionary.source_order {
         let cd = &dictionary.chunk_descriptors[*index as usize];
         let hash = HashSum::from_vec(cd.checksum.clone());
-        let chunk_path = chunk_path_from_hash(&hash);
+        let chunk_path = if store.is_zero_chunk(&hash, cd.source_size as usize) {
+            None
+        } else {
+            Some(chunk_path_from_hash(&hash))
+        };
         chunk_location_map.insert(
             ChunkOffsetSize::new(offset, cd.source_size as usize),
             chunk_path,
@@ -101,56 +122,111 @@ fn make_device(
     }
 }
 
-async fn mount_ihop(mut backend_file: File, root_path: &Path, nbd_dev: &Path, block_size: u32) {
-    let mut dict_size_buf = vec![0; std::mem::size_of::<u64>()];
-    backend_file
-        .read_exact(&mut dict_size_buf)
-        .await
-        .expect("read dictionary size");
-    let dict_size = u64::from_le_bytes((&dict_size_buf[..]).try_into().unwrap());
-    let mut dict_buf = vec![0; dict_size as usize];
-    backend_file
-        .read_exact(&mut dict_buf)
-        .await
-        .expect("read dictionary");
-    {
-        let mut expected_checksum = vec![0; 64];
-        backend_file
-            .read_exact(&mut expected_checksum)
-            .await
-            .expect("read checksum");
-
-        let mut hasher = Blake2b::new();
-        hasher.update(&crate::STORE_MAGIC[..]);
-        hasher.update(&dict_size_buf[..]);
-        hasher.update(&dict_buf[..]);
-        let checksum = hasher.finalize().to_vec();
-
-        if checksum != expected_checksum {
-            panic!(
-                "header checksum mismatch (expected {:?}, was {:?})",
-                expected_checksum, checksum
-            );
+// Wait for the nbd device to come up and open a dm-verity device on top of it.
+async fn open_verity(
+    nbd_dev: PathBuf,
+    name: String,
+    hash_tree: PathBuf,
+    verity: crate::storedict::VerityParameters,
+) {
+    let size_path = Path::new("/sys/block")
+        .join(nbd_dev.file_name().expect("nbd device name"))
+        .join("size");
+    loop {
+        match tokio::fs::read_to_string(&size_path).await {
+            Ok(size) if size.trim() != "0" => break,
+            _ => tokio::time::delay_for(Duration::from_millis(100)).await,
         }
     }
+    let root_hash: String = verity
+        .root_hash
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    info!(
+        "open verity device {} on {} (root hash {})",
+        name,
+        nbd_dev.display(),
+        root_hash
+    );
+    let status = tokio::task::spawn_blocking(move || {
+        std::process::Command::new("veritysetup")
+            .arg("open")
+            .arg(&nbd_dev)
+            .arg(&name)
+            .arg(&hash_tree)
+            .arg(&root_hash)
+            .status()
+    })
+    .await
+    .expect("run veritysetup")
+    .expect("run veritysetup");
+    if !status.success() {
+        panic!("veritysetup failed ({})", status);
+    }
+}
 
-    let dictionary: crate::storedict::StoreDictionary =
-        prost::Message::decode(&dict_buf[..]).expect("decode dictionary");
-
+async fn mount_ihop(
+    backend: &Path,
+    root_path: &Path,
+    nbd_dev: &Path,
+    block_size: u32,
+    verity_name: Option<&str>,
+    allow_downgrade: bool,
+) -> Result<(), CloneError> {
+    let dictionary = read_dictionary(backend).await;
+    check_security_version(root_path, dictionary.security_version, allow_downgrade).map_err(
+        |minimum| CloneError::Downgrade {
+            version: dictionary.security_version,
+            minimum,
+        },
+    )?;
+    if let Some(name) = verity_name {
+        let verity = dictionary
+            .verity
+            .clone()
+            .unwrap_or_else(|| panic!("no verity root hash stored in {}", backend.display()));
+        tokio::spawn(open_verity(
+            nbd_dev.to_path_buf(),
+            name.to_string(),
+            root_path.join(&verity.hash_tree),
+            verity,
+        ));
+    }
     let device = make_device(root_path, &dictionary, block_size);
     nbd_async::serve_local_nbd(nbd_dev, device.block_size, device.block_count, device)
         .await
         .expect("mount");
+    Ok(())
 }
 
-pub async fn mount(backend: &Path, nbd_dev: &Path, block_size: u32) {
+pub async fn mount(
+    backend: &Path,
+    nbd_dev: &Path,
+    block_size: u32,
+    lock_wait: LockWait,
+    verity_name: Option<&str>,
+    allow_downgrade: bool,
+) -> Result<(), CloneError> {
     let mut backend_file = File::open(backend).await.expect("open");
     let mut magic = vec![0; 6];
     backend_file.read_exact(&mut magic).await.expect("read");
     if &magic[..] == crate::STORE_MAGIC {
         info!("mount ihop {} on {}", backend.display(), nbd_dev.display());
-        let root_path = backend.parent().expect("store root");
-        mount_ihop(backend_file, root_path, nbd_dev, block_size).await;
+        let root_path = &store_root_of(backend);
+        // Hold a shared lock for as long as the device is mounted
+        let _lock = StoreLock::acquire(root_path, LockMode::Shared, lock_wait)
+            .await
+            .ok_or_else(|| CloneError::StoreLocked(root_path.to_path_buf()))?;
+        mount_ihop(
+            backend,
+            root_path,
+            nbd_dev,
+            block_size,
+            verity_name,
+            allow_downgrade,
+        )
+        .await?;
     } else {
         info!(
             "mount regular file {} on {} with block size {}",
@@ -160,4 +236,5 @@ pub async fn mount(backend: &Path, nbd_dev: &Path, block_size: u32) {
         );
         mount_file::mount(backend_file, nbd_dev, block_size).await;
     }
+    Ok(())
 }
@@ -0,0 +1,98 @@
+use bitar::HashSum;
+use reqwest::StatusCode;
+use std::path::PathBuf;
+use url::Url;
+
+use crate::store::chunk_path_from_hash;
+
+#[derive(Debug)]
+pub enum PeerError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    Corrupt(HashSum),
+}
+impl std::error::Error for PeerError {}
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "http error: {}", err),
+            Self::Io(err) => write!(f, "i/o error: {}", err),
+            Self::Corrupt(hash) => write!(f, "chunk {} is corrupt", hash),
+        }
+    }
+}
+impl From<reqwest::Error> for PeerError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+impl From<std::io::Error> for PeerError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// Another ihop store to fetch chunks from. Either a local directory or served
+// over http, by ihop serve-http or any file server.
+#[derive(Debug, Clone)]
+pub enum PeerStore {
+    Local(PathBuf),
+    Remote { url: Url, client: reqwest::Client },
+}
+
+impl PeerStore {
+    pub fn new(location: &str, client: &reqwest::Client) -> Self {
+        match location.parse::<Url>() {
+            Ok(mut url) if url.scheme() == "http" || url.scheme() == "https" => {
+                // Store root is a directory
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Self::Remote {
+                    url,
+                    client: client.clone(),
+                }
+            }
+            _ => Self::Local(PathBuf::from(location)),
+        }
+    }
+
+    pub fn source(&self) -> String {
+        match self {
+            Self::Local(path) => format!("{}", path.display()),
+            Self::Remote { url, .. } => url.to_string(),
+        }
+    }
+
+    // Read a file of the store, None if not found.
+    pub async fn fetch_file(&self, path: &str) -> Result<Option<Vec<u8>>, PeerError> {
+        match self {
+            Self::Local(root) => match tokio::fs::read(root.join(path)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            Self::Remote { url, client } => {
+                let url = url.join(path).expect("store file url");
+                let response = client.get(url).send().await?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+            }
+        }
+    }
+
+    // Fetch a chunk by hash and verify it, None if the store doesn't have it.
+    pub async fn fetch_chunk(&self, hash: &HashSum) -> Result<Option<Vec<u8>>, PeerError> {
+        let path = chunk_path_from_hash(hash);
+        let chunk = match self.fetch_file(&path.to_string_lossy()).await? {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+        if HashSum::b2_digest(&chunk, hash.len()) != *hash {
+            return Err(PeerError::Corrupt(hash.clone()));
+        }
+        Ok(Some(chunk))
+    }
+}
@@ -0,0 +1,106 @@
+use log::*;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+#[derive(Default)]
+struct Totals {
+    chunks_written: u64,
+    bytes_written: u64,
+    bytes_fetched: u64,
+}
+
+// Reports progress as JSON lines, one object per event. Each event has an
+// "event" name and the milliseconds since start in "elapsed_ms".
+pub struct Progress {
+    output: Mutex<Option<Box<dyn Write + Send>>>,
+    totals: Mutex<Totals>,
+    start: Instant,
+}
+
+impl Progress {
+    // Progress which reports nothing
+    pub fn none() -> Self {
+        Self::new(None)
+    }
+
+    pub fn json(output: Box<dyn Write + Send>) -> Self {
+        Self::new(Some(output))
+    }
+
+    fn new(output: Option<Box<dyn Write + Send>>) -> Self {
+        Self {
+            output: Mutex::new(output),
+            totals: Mutex::new(Totals::default()),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn event(&self, name: &str, mut event: Value) {
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        let writer = match output.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        event["event"] = json!(name);
+        event["elapsed_ms"] = json!(self.start.elapsed().as_millis() as u64);
+        if let Err(err) = writeln!(writer, "{}", event).and_then(|_| writer.flush()) {
+            // Progress is only informative, don't fail the operation
+            warn!("failed to write progress ({}), progress disabled", err);
+            *output = None;
+        }
+    }
+
+    // Report a chunk written to store. Fetched is the number of bytes
+    // transferred to get the chunk.
+    pub fn chunk_written(&self, checksum: &bitar::HashSum, size: usize, from: &str, fetched: u64) {
+        let event = {
+            let mut totals = self.totals.lock().unwrap();
+            totals.chunks_written += 1;
+            totals.bytes_written += size as u64;
+            totals.bytes_fetched += fetched;
+            json!({
+                "checksum": checksum.to_string(),
+                "size": size,
+                "from": from,
+                "chunks_written": totals.chunks_written,
+                "bytes_written": totals.bytes_written,
+                "bytes_fetched": totals.bytes_fetched,
+            })
+        };
+        self.event("chunk_written", event);
+    }
+
+    pub fn done(&self) {
+        let event = {
+            let totals = self.totals.lock().unwrap();
+            json!({
+                "chunks_written": totals.chunks_written,
+                "bytes_written": totals.bytes_written,
+                "bytes_fetched": totals.bytes_fetched,
+            })
+        };
+        self.event("done", event);
+    }
+
+    // Report the operation as failed, the last event of a failing run
+    pub fn error(&self, error: &str, exit_code: i32) {
+        self.event(
+            "error",
+            json!({
+                "error": error,
+                "exit_code": exit_code,
+            }),
+        );
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // Still end with an error event when failing by a panic
+        if std::thread::panicking() {
+            self.error("aborted by panic", 101);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::stream::{Stream, StreamExt};
+use reqwest::header::RANGE;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum RemoteError {
+    Http(reqwest::Error),
+    UnexpectedEnd,
+    RequestNotClonable,
+    Stalled(Duration),
+}
+impl std::error::Error for RemoteError {}
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "http error: {}", err),
+            Self::UnexpectedEnd => write!(f, "unexpected end"),
+            Self::RequestNotClonable => write!(f, "request is not clonable"),
+            Self::Stalled(timeout) => write!(f, "no data received for {:?}", timeout),
+        }
+    }
+}
+
+async fn stall_timeout<T, F>(timeout: Option<Duration>, future: F) -> Result<T, RemoteError>
+where
+    F: Future<Output = Result<T, reqwest::Error>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| RemoteError::Stalled(timeout))?
+            .map_err(RemoteError::Http),
+        None => future.await.map_err(RemoteError::Http),
+    }
+}
+
+// Reader of a remote archive using http range requests. Fails if no data has
+// been received for the stall timeout, however long a chunk takes to receive.
+// Used to give up on slow mirrors.
+pub struct RemoteReader {
+    request: reqwest::RequestBuilder,
+    stall_timeout: Option<Duration>,
+}
+
+impl RemoteReader {
+    pub fn new(request: reqwest::RequestBuilder, stall_timeout: Option<Duration>) -> Self {
+        Self {
+            request,
+            stall_timeout,
+        }
+    }
+    async fn send(&self, offset: u64, size: u64) -> Result<reqwest::Response, RemoteError> {
+        let request = self
+            .request
+            .try_clone()
+            .ok_or(RemoteError::RequestNotClonable)?
+            .header(RANGE, format!("bytes={}-{}", offset, offset + size - 1));
+        let response = stall_timeout(self.stall_timeout, request.send()).await?;
+        response.error_for_status().map_err(RemoteError::Http)
+    }
+    // Receive until buf holds size bytes.
+    async fn fill(
+        &self,
+        response: &mut reqwest::Response,
+        buf: &mut BytesMut,
+        size: usize,
+    ) -> Result<(), RemoteError> {
+        while buf.len() < size {
+            match stall_timeout(self.stall_timeout, response.chunk()).await? {
+                Some(data) => buf.extend_from_slice(&data),
+                None => return Err(RemoteError::UnexpectedEnd),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl bitar::Reader for RemoteReader {
+    type Error = RemoteError;
+    async fn read_at<'a>(&'a mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+        if size == 0 {
+            return Ok(Bytes::new());
+        }
+        let mut response = self.send(offset, size as u64).await?;
+        let mut buf = BytesMut::with_capacity(size);
+        self.fill(&mut response, &mut buf, size).await?;
+        buf.truncate(size);
+        Ok(buf.freeze())
+    }
+    fn read_chunks<'a>(
+        &'a mut self,
+        start_offset: u64,
+        chunk_sizes: &'a [usize],
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+        let this = &*self;
+        let total_size: u64 = chunk_sizes.iter().map(|size| *size as u64).sum();
+        Box::pin(futures::stream::unfold(
+            Some((None, BytesMut::new(), 0)),
+            move |state| async move {
+                let (mut response, mut buf, index): (Option<reqwest::Response>, BytesMut, usize) =
+                    state?;
+                let chunk_size = *chunk_sizes.get(index)?;
+                if response.is_none() {
+                    match this.send(start_offset, total_size).await {
+                        Ok(new_response) => response = Some(new_response),
+                        // End the stream after the error
+                        Err(err) => return Some((Err(err), None)),
+                    }
+                }
+                match this
+                    .fill(response.as_mut().unwrap(), &mut buf, chunk_size)
+                    .await
+                {
+                    Ok(()) => {
+                        let chunk = buf.split_to(chunk_size).freeze();
+                        Some((Ok(chunk), Some((response, buf, index + 1))))
+                    }
+                    Err(err) => Some((Err(err), None)),
+                }
+            },
+        ))
+    }
+}
+
+struct TokenBucket {
+    // Bytes per second, None if unlimited
+    rate: Option<u64>,
+    // Available bytes, negative when in debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Take bytes from the bucket and return the time to wait before the
+    // bytes may be used.
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        let rate = match self.rate {
+            Some(rate) => rate as f64,
+            None => return Duration::from_secs(0),
+        };
+        // Allow bursts of up to a second worth of data
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+// Shared download rate limit which can be adjusted while in use.
+#[derive(Clone)]
+pub struct RateLimit(Arc<Mutex<TokenBucket>>);
+
+impl std::fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RateLimit({:?})", self.0.lock().unwrap().rate)
+    }
+}
+
+impl RateLimit {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self(Arc::new(Mutex::new(TokenBucket {
+            rate,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        })))
+    }
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = 0.0;
+    }
+    async fn consume(&self, bytes: usize) {
+        let wait = self.0.lock().unwrap().take(bytes);
+        if wait > Duration::from_secs(0) {
+            tokio::time::delay_for(wait).await;
+        }
+    }
+}
+
+// Reader which throttles the data received from the inner reader.
+pub struct RateLimited<R> {
+    inner: R,
+    limit: RateLimit,
+}
+
+impl<R> RateLimited<R> {
+    pub fn new(inner: R, limit: RateLimit) -> Self {
+        Self { inner, limit }
+    }
+}
+
+#[async_trait]
+impl<R> bitar::Reader for RateLimited<R>
+where
+    R: bitar::Reader + Send,
+    R::Error: Send,
+{
+    type Error = R::Error;
+    async fn read_at<'a>(&'a mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+        let buf = self.inner.read_at(offset, size).await?;
+        self.limit.consume(buf.len()).await;
+        Ok(buf)
+    }
+    fn read_chunks<'a>(
+        &'a mut self,
+        start_offset: u64,
+        chunk_sizes: &'a [usize],
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+        let limit = self.limit.clone();
+        // The inner stream is not polled while waiting, which also holds back
+        // the transfer.
+        Box::pin(
+            self.inner
+                .read_chunks(start_offset, chunk_sizes)
+                .then(move |result| {
+                    let limit = limit.clone();
+                    async move {
+                        if let Ok(buf) = &result {
+                            limit.consume(buf.len()).await;
+                        }
+                        result
+                    }
+                }),
+        )
+    }
+}
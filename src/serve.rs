@@ -0,0 +1,189 @@
+use bytes::Bytes;
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::*;
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::store;
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+
+// Size of the reads when streaming a file
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+// Map a request path to a file in store. Only dictionaries in the store root
+// and chunk files are served, using the same layout as the store on disk so
+// that any static file server can be used as well.
+fn store_file(store_root: &Path, path: &str) -> Option<(PathBuf, bool)> {
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-+".contains(c))
+    };
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match &segments[..] {
+        [name] if valid_name(name) && *name != "chunks" => Some((store_root.join(name), true)),
+        ["chunks", subdir, name]
+            if subdir.len() == 4
+                && subdir.chars().all(|c| c.is_ascii_hexdigit())
+                && name.ends_with(".chunk")
+                && valid_name(name) =>
+        {
+            Some((store_root.join("chunks").join(subdir).join(name), false))
+        }
+        _ => None,
+    }
+}
+
+// Parse a single range header value ("bytes=start-end", "bytes=start-" or
+// "bytes=-suffix") into start and end (exclusive) offsets.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        // Multiple ranges not supported
+        return None;
+    }
+    let mut split = range.splitn(2, '-');
+    let (start, end) = (split.next()?.trim(), split.next()?.trim());
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        (false, false) => (start.parse().ok()?, end.parse::<u64>().ok()? + 1),
+        (false, true) => (start.parse().ok()?, size),
+        (true, false) => (size.saturating_sub(end.parse().ok()?), size),
+        (true, true) => return None,
+    };
+    let end = std::cmp::min(end, size);
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+// Test if a file in the store root is a complete dictionary, from its
+// header. Other files (partly written, temporary or left there by other
+// tools) are not served.
+async fn is_dictionary(file: &mut File, size: u64) -> bool {
+    let mut header = [0; store::DICTIONARY_PRE_HEADER_SIZE];
+    file.read_exact(&mut header).await.is_ok() && store::is_dictionary_header(&header, size)
+}
+
+// Stream part of a file, holding the store lock until done.
+fn file_body(file: File, start: u64, end: u64, lock: StoreLock) -> Body {
+    let stream = futures::stream::unfold(
+        (file, end - start, lock),
+        |(mut file, left, lock)| async move {
+            if left == 0 {
+                return None;
+            }
+            let read_size = std::cmp::min(left, READ_BUF_SIZE as u64);
+            let mut buf = vec![0; read_size as usize];
+            match file.read_exact(&mut buf).await {
+                Ok(_) => Some((Ok(Bytes::from(buf)), (file, left - read_size, lock))),
+                // End the stream after the error
+                Err(err) => Some((Err(err), (file, 0, lock))),
+            }
+        },
+    );
+    Body::wrap_stream(stream)
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+async fn handle(store_root: Arc<PathBuf>, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let (path, dictionary) = match store_file(&store_root, request.uri().path()) {
+        Some(file) => file,
+        None => return status_response(StatusCode::NOT_FOUND),
+    };
+    // Keep the store from being modified (eg by a gc) while serving
+    let lock = match StoreLock::acquire(&store_root, LockMode::Shared, LockWait::No).await {
+        Some(lock) => lock,
+        None => return status_response(StatusCode::SERVICE_UNAVAILABLE),
+    };
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return status_response(StatusCode::NOT_FOUND),
+    };
+    let size = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return status_response(StatusCode::NOT_FOUND),
+    };
+    if dictionary && !is_dictionary(&mut file, size).await {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    let (status, start, end) = match request.headers().get(RANGE) {
+        None => (StatusCode::OK, 0, size),
+        Some(range) => match range.to_str().ok().and_then(|r| parse_range(r, size)) {
+            Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end),
+            None => {
+                let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                response
+                    .headers_mut()
+                    .insert(CONTENT_RANGE, format!("bytes */{}", size).parse().unwrap());
+                return response;
+            }
+        },
+    };
+    debug!(
+        "serve {} bytes {}-{} of {}",
+        request.uri().path(),
+        start,
+        end,
+        size
+    );
+    let body = if request.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        if let Err(err) = file.seek(SeekFrom::Start(start)).await {
+            warn!("failed to seek in {}: {}", path.display(), err);
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        file_body(file, start, end, lock)
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(ACCEPT_RANGES, "bytes".parse().unwrap());
+    headers.insert(CONTENT_LENGTH, (end - start).into());
+    if status == StatusCode::PARTIAL_CONTENT {
+        headers.insert(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, size)
+                .parse()
+                .unwrap(),
+        );
+    }
+    response
+}
+
+// Serve the dictionaries and chunks of a store over http.
+pub async fn serve(store_root: &Path, addr: SocketAddr) {
+    let store_root = Arc::new(store_root.to_path_buf());
+    info!("serving store {} on http://{}", store_root.display(), addr);
+    let make_service = make_service_fn(move |_conn| {
+        let store_root = store_root.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let store_root = store_root.clone();
+                async move { Ok::<_, Infallible>(handle(store_root, request).await) }
+            }))
+        }
+    });
+    Server::bind(&addr)
+        .serve(make_service)
+        .await
+        .expect("serve http");
+}
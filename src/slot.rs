@@ -0,0 +1,536 @@
+use log::*;
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::store::{check_security_version, read_dictionary, record_security_version};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+
+// Slots are symlinks in the store root pointing at a dictionary in the same
+// directory. `current` is the release in use and `previous` the one it
+// replaced, other slots (like `next`) hold releases waiting to be switched to.
+pub const CURRENT: &str = "current";
+pub const PREVIOUS: &str = "previous";
+
+// Every change of slots is written to the journal before being applied, and
+// marked done after. A change not marked done is applied again on next use.
+const JOURNAL_FILE: &str = ".slots.journal";
+
+#[derive(Debug)]
+pub enum SlotError {
+    StoreLocked(PathBuf),
+    InvalidName(String),
+    NoSuchSlot(String),
+    NoSuchDictionary(String),
+    Downgrade { version: u64, minimum: u64 },
+}
+impl std::error::Error for SlotError {}
+impl std::fmt::Display for SlotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StoreLocked(path) => write!(f, "store {} is locked", path.display()),
+            Self::InvalidName(name) => write!(f, "invalid slot or release name {:?}", name),
+            Self::NoSuchSlot(name) => write!(f, "slot {} is not set", name),
+            Self::NoSuchDictionary(name) => write!(f, "no dictionary {} in store", name),
+            Self::Downgrade { version, minimum } => write!(
+                f,
+                "security version {} is lower than {} of store (see --allow-downgrade)",
+                version, minimum
+            ),
+        }
+    }
+}
+impl SlotError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::StoreLocked(_) => 4,
+            Self::Downgrade { .. } => 8,
+            _ => 1,
+        }
+    }
+}
+
+// Slot change as slot name and the dictionary to point at (None to remove).
+type Change = (String, Option<String>);
+
+// Names of slots and dictionaries are plain file names in the store root.
+pub(crate) fn check_name(name: &str) -> Result<(), SlotError> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(SlotError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn is_symlink(path: &Path) -> bool {
+    std::fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+// Check the names of a slot and the dictionary to point it at. Slots and
+// dictionaries share the store root, so a slot must not replace a dictionary
+// and a dictionary must not be a slot.
+pub(crate) fn check_names(
+    store_root: &Path,
+    slot: &str,
+    dictionary: &str,
+) -> Result<(), SlotError> {
+    check_name(slot)?;
+    check_name(dictionary)?;
+    let slot_path = store_root.join(slot);
+    if slot_path.exists() && !is_symlink(&slot_path) {
+        return Err(SlotError::InvalidName(slot.to_string()));
+    }
+    if dictionary == slot
+        || dictionary == CURRENT
+        || dictionary == PREVIOUS
+        || is_symlink(&store_root.join(dictionary))
+    {
+        return Err(SlotError::InvalidName(dictionary.to_string()));
+    }
+    Ok(())
+}
+
+// Dictionary the slot points at, if set.
+pub fn read_slot(store_root: &Path, slot: &str) -> Option<String> {
+    std::fs::read_link(store_root.join(slot))
+        .ok()
+        .map(|target| target.to_string_lossy().to_string())
+}
+
+fn sync_dir(dir: &Path) {
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .unwrap_or_else(|err| panic!("failed to sync {}: {}", dir.display(), err));
+}
+
+fn append_journal_raw(path: &Path, buf: &[u8]) {
+    let mut journal = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap_or_else(|err| panic!("failed to open {}: {}", path.display(), err));
+    journal.write_all(buf).expect("write journal");
+    journal.sync_all().expect("sync journal");
+}
+
+fn append_journal(store_root: &Path, entry: serde_json::Value) {
+    append_journal_raw(
+        &store_root.join(JOURNAL_FILE),
+        format!("{}\n", entry).as_bytes(),
+    );
+}
+
+fn journal_entry(op: &str, state: &str, changes: &[Change]) -> serde_json::Value {
+    let slots: serde_json::Map<String, serde_json::Value> = changes
+        .iter()
+        .map(|(slot, target)| (slot.clone(), json!(target)))
+        .collect();
+    json!({
+        "time": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0),
+        "op": op,
+        "state": state,
+        "slots": slots,
+    })
+}
+
+// Repoint slots by renaming a new symlink over the old one.
+fn apply_changes(store_root: &Path, changes: &[Change]) {
+    for (slot, target) in changes {
+        let path = store_root.join(slot);
+        match target {
+            Some(target) => {
+                let tmp_path = store_root.join(format!(".{}.tmp", slot));
+                let _ = std::fs::remove_file(&tmp_path);
+                std::os::unix::fs::symlink(target, &tmp_path).unwrap_or_else(|err| {
+                    panic!("failed to create {}: {}", tmp_path.display(), err)
+                });
+                std::fs::rename(&tmp_path, &path)
+                    .unwrap_or_else(|err| panic!("failed to update {}: {}", path.display(), err));
+            }
+            None => match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    panic!("failed to remove {}: {}", path.display(), err)
+                }
+                _ => {}
+            },
+        }
+    }
+    sync_dir(store_root);
+}
+
+fn transaction(store_root: &Path, op: &str, changes: &[Change]) {
+    append_journal(store_root, journal_entry(op, "begin", changes));
+    apply_changes(store_root, changes);
+    append_journal(store_root, journal_entry(op, "done", changes));
+    for (slot, target) in changes {
+        match target {
+            Some(target) => info!("{} -> {}", slot, target),
+            None => info!("{} removed", slot),
+        }
+    }
+}
+
+// Finish a change interrupted by a crash or power loss.
+fn recover(store_root: &Path) {
+    let path = store_root.join(JOURNAL_FILE);
+    let journal = match std::fs::read(&path) {
+        Ok(journal) => journal,
+        Err(_) => return,
+    };
+    if journal.last().map(|b| *b != b'\n').unwrap_or(false) {
+        // Terminate a torn entry, to not have the next one appended to it
+        append_journal_raw(&path, b"\n");
+    }
+    let journal = String::from_utf8_lossy(&journal);
+    let last = match journal.lines().last() {
+        Some(last) => last,
+        None => return,
+    };
+    let entry: serde_json::Value = match serde_json::from_str(last) {
+        Ok(entry) => entry,
+        Err(_) => {
+            // Torn write of a begin entry, nothing has been applied
+            warn!("ignoring incomplete journal entry in {}", path.display());
+            return;
+        }
+    };
+    if entry["state"] != "begin" {
+        return;
+    }
+    let op = entry["op"].as_str().unwrap_or("unknown");
+    let changes: Vec<Change> = entry["slots"]
+        .as_object()
+        .map(|slots| {
+            slots
+                .iter()
+                .map(|(slot, target)| (slot.clone(), target.as_str().map(String::from)))
+                .collect()
+        })
+        .unwrap_or_default();
+    warn!("completing interrupted slot change ({})", op);
+    apply_changes(store_root, &changes);
+    append_journal(store_root, journal_entry(op, "done", &changes));
+}
+
+async fn security_version(store_root: &Path, dictionary: &str) -> u64 {
+    read_dictionary(&store_root.join(dictionary))
+        .await
+        .security_version
+}
+
+// Refuse releases older than the store security version.
+async fn check_downgrade(
+    store_root: &Path,
+    dictionary: &str,
+    allow_downgrade: bool,
+) -> Result<(), SlotError> {
+    let version = security_version(store_root, dictionary).await;
+    check_security_version(store_root, version, allow_downgrade)
+        .map_err(|minimum| SlotError::Downgrade { version, minimum })
+}
+
+// Slot changes take their own lock rather than the write lock, which clones
+// hold for as long as they run, to not keep a device from booting or
+// switching release during a background update.
+async fn lock(store_root: &Path, lock_wait: LockWait) -> Result<StoreLock, SlotError> {
+    let lock = StoreLock::acquire(store_root, LockMode::Slots, lock_wait)
+        .await
+        .ok_or_else(|| SlotError::StoreLocked(store_root.to_path_buf()))?;
+    recover(store_root);
+    Ok(lock)
+}
+
+// Point a slot at a dictionary in store.
+pub async fn set(
+    store_root: &Path,
+    slot: &str,
+    dictionary: &str,
+    lock_wait: LockWait,
+) -> Result<(), SlotError> {
+    check_name(slot)?;
+    if slot == CURRENT || slot == PREVIOUS {
+        // Use switch for current, to keep previous in sync
+        return Err(SlotError::InvalidName(slot.to_string()));
+    }
+    let _lock = lock(store_root, lock_wait).await?;
+    check_names(store_root, slot, dictionary)?;
+    if !store_root.join(dictionary).is_file() {
+        return Err(SlotError::NoSuchDictionary(dictionary.to_string()));
+    }
+    transaction(
+        store_root,
+        "install",
+        &[(slot.to_string(), Some(dictionary.to_string()))],
+    );
+    // A reinstalled release which wasn't confirmed gets new boot attempts
+    if !read_boot_state(store_root, dictionary).confirmed {
+        let _ = std::fs::remove_file(boot_state_path(store_root, dictionary));
+    }
+    Ok(())
+}
+
+// Make the release of a slot current, keeping the replaced one as previous.
+pub async fn switch(
+    store_root: &Path,
+    slot: &str,
+    allow_downgrade: bool,
+    lock_wait: LockWait,
+) -> Result<(), SlotError> {
+    check_name(slot)?;
+    if slot == CURRENT || slot == PREVIOUS {
+        return Err(SlotError::InvalidName(slot.to_string()));
+    }
+    let _lock = lock(store_root, lock_wait).await?;
+    let target =
+        read_slot(store_root, slot).ok_or_else(|| SlotError::NoSuchSlot(slot.to_string()))?;
+    if !store_root.join(&target).is_file() {
+        return Err(SlotError::NoSuchDictionary(target));
+    }
+    check_downgrade(store_root, &target, allow_downgrade).await?;
+    let mut changes = vec![(CURRENT.to_string(), Some(target))];
+    if let Some(current) = read_slot(store_root, CURRENT) {
+        changes.push((PREVIOUS.to_string(), Some(current)));
+    }
+    changes.push((slot.to_string(), None));
+    transaction(store_root, "switch", &changes);
+    Ok(())
+}
+
+// Swap back to the previous release.
+pub async fn rollback(
+    store_root: &Path,
+    allow_downgrade: bool,
+    lock_wait: LockWait,
+) -> Result<(), SlotError> {
+    let _lock = lock(store_root, lock_wait).await?;
+    let previous = read_slot(store_root, PREVIOUS)
+        .ok_or_else(|| SlotError::NoSuchSlot(PREVIOUS.to_string()))?;
+    if !store_root.join(&previous).is_file() {
+        return Err(SlotError::NoSuchDictionary(previous));
+    }
+    check_downgrade(store_root, &previous, allow_downgrade).await?;
+    let current = read_slot(store_root, CURRENT);
+    transaction(
+        store_root,
+        "rollback",
+        &[
+            (CURRENT.to_string(), Some(previous)),
+            (PREVIOUS.to_string(), current),
+        ],
+    );
+    Ok(())
+}
+
+// Boot state of a release, kept next to its dictionary. A release is tried a
+// limited number of times until confirmed to be good.
+#[derive(Debug, Clone, Copy, Default)]
+struct BootState {
+    attempts: u32,
+    confirmed: bool,
+}
+
+fn boot_state_path(store_root: &Path, dictionary: &str) -> PathBuf {
+    store_root.join(format!(".{}.boot", dictionary))
+}
+
+fn read_boot_state(store_root: &Path, dictionary: &str) -> BootState {
+    let state: serde_json::Value = match std::fs::read(boot_state_path(store_root, dictionary)) {
+        Ok(buf) => serde_json::from_slice(&buf).unwrap_or_default(),
+        Err(_) => return BootState::default(),
+    };
+    BootState {
+        attempts: state["attempts"].as_u64().unwrap_or(0) as u32,
+        confirmed: state["confirmed"].as_bool().unwrap_or(false),
+    }
+}
+
+fn write_boot_state(store_root: &Path, dictionary: &str, state: BootState) {
+    let path = boot_state_path(store_root, dictionary);
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let buf = json!({ "attempts": state.attempts, "confirmed": state.confirmed }).to_string();
+    let mut file = std::fs::File::create(&tmp_path)
+        .unwrap_or_else(|err| panic!("failed to create {}: {}", tmp_path.display(), err));
+    file.write_all(buf.as_bytes()).expect("write boot state");
+    file.sync_all().expect("sync boot state");
+    std::fs::rename(&tmp_path, &path)
+        .unwrap_or_else(|err| panic!("failed to update {}: {}", path.display(), err));
+    sync_dir(store_root);
+}
+
+// Test if a path is the current slot of a store.
+pub fn is_current(path: &Path) -> bool {
+    path.file_name() == Some(std::ffi::OsStr::new(CURRENT)) && is_symlink(path)
+}
+
+// Count a boot attempt of the current release and return the dictionary to
+// mount. An unconfirmed release which has been tried max_attempts times
+// already is replaced by the previous release, if that one is confirmed.
+pub async fn boot(
+    store_root: &Path,
+    max_attempts: u32,
+    lock_wait: LockWait,
+) -> Result<PathBuf, SlotError> {
+    let _lock = lock(store_root, lock_wait).await?;
+    let mut current =
+        read_slot(store_root, CURRENT).ok_or_else(|| SlotError::NoSuchSlot(CURRENT.to_string()))?;
+    let mut state = read_boot_state(store_root, &current);
+    if !state.confirmed && state.attempts >= max_attempts {
+        match read_slot(store_root, PREVIOUS) {
+            Some(previous)
+                if read_boot_state(store_root, &previous).confirmed
+                    && check_downgrade(store_root, &previous, false).await.is_ok() =>
+            {
+                warn!(
+                    "{} not confirmed after {} attempts, falling back to {}",
+                    current, state.attempts, previous
+                );
+                transaction(
+                    store_root,
+                    "fallback",
+                    &[
+                        (CURRENT.to_string(), Some(previous.clone())),
+                        (PREVIOUS.to_string(), Some(current)),
+                    ],
+                );
+                current = previous;
+                state = read_boot_state(store_root, &current);
+            }
+            _ => warn!(
+                "{} not confirmed after {} attempts and no good release to fall back to",
+                current, state.attempts
+            ),
+        }
+    }
+    if !state.confirmed {
+        state.attempts += 1;
+        write_boot_state(store_root, &current, state);
+        info!(
+            "boot attempt {} of {} (max {})",
+            state.attempts, current, max_attempts
+        );
+    }
+    Ok(store_root.join(current))
+}
+
+// Mark the current release as good, raising the store security version to
+// that of the release.
+pub async fn confirm(store_root: &Path, lock_wait: LockWait) -> Result<(), SlotError> {
+    let _lock = lock(store_root, lock_wait).await?;
+    let current =
+        read_slot(store_root, CURRENT).ok_or_else(|| SlotError::NoSuchSlot(CURRENT.to_string()))?;
+    let mut state = read_boot_state(store_root, &current);
+    state.confirmed = true;
+    write_boot_state(store_root, &current, state);
+    record_security_version(store_root, security_version(store_root, &current).await);
+    info!("{} confirmed after {} attempts", current, state.attempts);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ihop-slot-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for dictionary in &["rel1", "rel2"] {
+            std::fs::write(dir.join(dictionary), b"").unwrap();
+        }
+        dir
+    }
+
+    fn journal_states(store_root: &Path) -> Vec<String> {
+        std::fs::read_to_string(store_root.join(JOURNAL_FILE))
+            .unwrap()
+            .lines()
+            // Skipping torn entries
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .map(|entry| entry["state"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn recover_completes_begun_change() {
+        let store_root = test_store("begun");
+        transaction(
+            &store_root,
+            "switch",
+            &[(CURRENT.to_string(), Some("rel1".to_string()))],
+        );
+        // Interrupted switch, journaled but only partly applied
+        let changes = [
+            (CURRENT.to_string(), Some("rel2".to_string())),
+            (PREVIOUS.to_string(), Some("rel1".to_string())),
+            ("next".to_string(), None),
+        ];
+        append_journal(&store_root, journal_entry("switch", "begin", &changes));
+        apply_changes(&store_root, &changes[..1]);
+        std::os::unix::fs::symlink("rel2", store_root.join("next")).unwrap();
+
+        recover(&store_root);
+        assert_eq!(read_slot(&store_root, CURRENT).as_deref(), Some("rel2"));
+        assert_eq!(read_slot(&store_root, PREVIOUS).as_deref(), Some("rel1"));
+        assert_eq!(read_slot(&store_root, "next"), None);
+        assert_eq!(
+            journal_states(&store_root),
+            vec!["begin", "done", "begin", "done"]
+        );
+
+        // Nothing left to do once done
+        recover(&store_root);
+        assert_eq!(journal_states(&store_root).len(), 4);
+        std::fs::remove_dir_all(&store_root).unwrap();
+    }
+
+    #[test]
+    fn recover_ignores_torn_entry() {
+        let store_root = test_store("torn");
+        transaction(
+            &store_root,
+            "install",
+            &[("next".to_string(), Some("rel1".to_string()))],
+        );
+        append_journal_raw(
+            &store_root.join(JOURNAL_FILE),
+            br#"{"op":"install","state":"beg"#,
+        );
+
+        recover(&store_root);
+        assert_eq!(read_slot(&store_root, "next").as_deref(), Some("rel1"));
+        // The torn entry is terminated, to not corrupt the next one
+        transaction(
+            &store_root,
+            "install",
+            &[("next".to_string(), Some("rel2".to_string()))],
+        );
+        assert_eq!(read_slot(&store_root, "next").as_deref(), Some("rel2"));
+        assert_eq!(
+            journal_states(&store_root),
+            vec!["begin", "done", "begin", "done"]
+        );
+        std::fs::remove_dir_all(&store_root).unwrap();
+    }
+
+    #[test]
+    fn slot_and_dictionary_names_are_kept_apart() {
+        let store_root = test_store("names");
+        transaction(
+            &store_root,
+            "install",
+            &[("next".to_string(), Some("rel1".to_string()))],
+        );
+        assert!(check_names(&store_root, "next", "rel2").is_ok());
+        assert!(check_names(&store_root, "rel1", "rel2").is_err());
+        assert!(check_names(&store_root, "other", "next").is_err());
+        assert!(check_names(&store_root, "rel3", "rel3").is_err());
+        assert!(check_names(&store_root, "other", CURRENT).is_err());
+        std::fs::remove_dir_all(&store_root).unwrap();
+    }
+}
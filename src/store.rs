@@ -0,0 +1,425 @@
+use async_trait::async_trait;
+use bitar::{clone::CloneOutput, ChunkIndex, HashSum};
+use blake2::{Blake2b, Digest};
+use log::*;
+use prost::Message;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use tokio::fs::{create_dir_all, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::storedict;
+use crate::STORE_MAGIC;
+
+pub fn build_store_header(dictionary: &storedict::StoreDictionary) -> Vec<u8> {
+    let mut header: Vec<u8> = vec![];
+    let mut hasher = Blake2b::new();
+    let mut dictionary_buf: Vec<u8> = Vec::new();
+
+    dictionary
+        .encode(&mut dictionary_buf)
+        .expect("encode dictionary");
+
+    // File magic indicating bita archive version 1
+    header.extend(STORE_MAGIC);
+    header.extend(&(dictionary_buf.len() as u64).to_le_bytes());
+    header.extend(dictionary_buf);
+
+    // Create and store hash of full header
+    hasher.update(&header);
+    header.extend(&hasher.finalize());
+    header
+}
+
+// Store holding the given dictionary. A dictionary given by a bare file
+// name is in the current directory.
+pub fn store_root_of(dictionary_path: &Path) -> PathBuf {
+    match dictionary_path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("./"),
+    }
+}
+
+// Parse a store dictionary file and verify its checksum.
+pub fn parse_dictionary(buf: &[u8]) -> Result<storedict::StoreDictionary, &'static str> {
+    let pre_header_size = STORE_MAGIC.len() + std::mem::size_of::<u64>();
+    if buf.len() < pre_header_size || &buf[..STORE_MAGIC.len()] != STORE_MAGIC {
+        return Err("not an ihop dictionary");
+    }
+    let dict_size = u64::from_le_bytes(buf[STORE_MAGIC.len()..pre_header_size].try_into().unwrap());
+    let dict_end = dict_size
+        .checked_add(pre_header_size as u64)
+        .filter(|end| end.saturating_add(64) <= buf.len() as u64)
+        .ok_or("dictionary is truncated")? as usize;
+    let mut hasher = Blake2b::new();
+    hasher.update(&buf[..dict_end]);
+    if hasher.finalize()[..] != buf[dict_end..dict_end + 64] {
+        return Err("dictionary checksum mismatch");
+    }
+    let dictionary = storedict::StoreDictionary::decode(&buf[pre_header_size..dict_end])
+        .map_err(|_| "failed to decode dictionary")?;
+    // Chunks of the image must all be described
+    let mut source_size: u64 = 0;
+    for index in &dictionary.source_order {
+        match dictionary.chunk_descriptors.get(*index as usize) {
+            Some(cd) => source_size += cd.source_size as u64,
+            None => return Err("chunk index out of range"),
+        }
+    }
+    if source_size != dictionary.source_total_size {
+        return Err("chunk sizes don't add up to image size");
+    }
+    Ok(dictionary)
+}
+
+// Read a store dictionary file and verify its checksum.
+pub async fn read_dictionary(path: &Path) -> storedict::StoreDictionary {
+    let buf = tokio::fs::read(path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err));
+    parse_dictionary(&buf).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+}
+
+// Find a dictionary in store of the image with the given source checksum.
+// Test the pre-header (magic and dictionary size) of a dictionary file of
+// the given size, to tell dictionaries from other files without reading them.
+pub const DICTIONARY_PRE_HEADER_SIZE: usize = 14;
+
+pub fn is_dictionary_header(header: &[u8], size: u64) -> bool {
+    if header.len() < DICTIONARY_PRE_HEADER_SIZE || &header[..STORE_MAGIC.len()] != STORE_MAGIC {
+        return false;
+    }
+    let dict_size = u64::from_le_bytes(
+        header[STORE_MAGIC.len()..DICTIONARY_PRE_HEADER_SIZE]
+            .try_into()
+            .unwrap(),
+    );
+    dict_size.checked_add(DICTIONARY_PRE_HEADER_SIZE as u64 + 64) == Some(size)
+}
+
+fn read_if_dictionary(path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+    use std::io::Read;
+    let mut file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut header = [0; DICTIONARY_PRE_HEADER_SIZE];
+    if file.read_exact(&mut header).is_err() || !is_dictionary_header(&header, size) {
+        return Ok(None);
+    }
+    let mut buf = header.to_vec();
+    file.read_to_end(&mut buf)?;
+    Ok(Some(buf))
+}
+
+pub fn find_dictionary(store_root: &Path, source_checksum: &[u8]) -> Option<PathBuf> {
+    std::fs::read_dir(store_root)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        // Only files looking like dictionaries are read in full
+        .find(|entry| match read_if_dictionary(&entry.path()) {
+            Ok(Some(buf)) => parse_dictionary(&buf)
+                .map(|dictionary| dictionary.source_checksum == source_checksum)
+                .unwrap_or(false),
+            _ => false,
+        })
+        .map(|entry| entry.path())
+}
+
+// Replace a dictionary file. Written to a temporary file first, to never
+// leave a partly written dictionary behind.
+pub async fn write_dictionary(path: &Path, dictionary: &storedict::StoreDictionary) {
+    write_dictionary_file(path, &build_store_header(dictionary)).await;
+}
+
+pub async fn write_dictionary_file(path: &Path, buf: &[u8]) {
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut file = File::create(&tmp_path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to create {}: {}", tmp_path.display(), err));
+    file.write_all(buf).await.expect("write dictionary");
+    file.sync_all().await.expect("sync dictionary");
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .expect("replace dictionary");
+}
+
+// Highest security version of a confirmed release in store. Releases with a
+// lower version are refused, to not reinstall known vulnerable releases.
+const SECURITY_VERSION_FILE: &str = ".security_version";
+
+pub fn read_security_version(store_root: &Path) -> u64 {
+    match std::fs::read_to_string(store_root.join(SECURITY_VERSION_FILE)) {
+        Ok(version) => version
+            .trim()
+            .parse()
+            .expect("failed to parse store security version"),
+        Err(_) => 0,
+    }
+}
+
+pub fn record_security_version(store_root: &Path, version: u64) {
+    if version <= read_security_version(store_root) {
+        return;
+    }
+    let path = store_root.join(SECURITY_VERSION_FILE);
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    std::fs::write(&tmp_path, format!("{}\n", version))
+        .and_then(|()| std::fs::File::open(&tmp_path)?.sync_all())
+        .and_then(|()| std::fs::rename(&tmp_path, &path))
+        .unwrap_or_else(|err| panic!("failed to write {}: {}", path.display(), err));
+    info!("store security version is now {}", version);
+}
+
+// Test if a release of the given security version may be used in store.
+// Returns the store security version if it's higher and no downgrade is
+// allowed. An allowed downgrade is logged.
+pub fn check_security_version(
+    store_root: &Path,
+    version: u64,
+    allow_downgrade: bool,
+) -> Result<(), u64> {
+    let minimum = read_security_version(store_root);
+    if version >= minimum {
+        return Ok(());
+    }
+    if !allow_downgrade {
+        return Err(minimum);
+    }
+    warn!(
+        "AUDIT: downgrade of {} from security version {} to {} allowed by --allow-downgrade",
+        store_root.display(),
+        minimum,
+        version
+    );
+    Ok(())
+}
+
+// Name of a release with its source checksum appended, to tell it apart from
+// another release published under the same name.
+pub fn checksum_name(name: &str, source_checksum: &[u8]) -> String {
+    let short: String = source_checksum[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}.{}", name, short)
+}
+
+pub fn chunk_path_from_hash(hash: &HashSum) -> PathBuf {
+    let subdir_bytes = 2;
+    let mut subdir_name = String::with_capacity(subdir_bytes * 2);
+    hash.slice()[..subdir_bytes]
+        .iter()
+        .for_each(|b| subdir_name.push_str(&format!("{:02x}", b)));
+    Path::new("chunks")
+        .join(subdir_name)
+        .join(format!("{}", hash))
+        .with_extension("chunk")
+}
+
+// Store of chunk files, each named by the chunk hash. Chunks of only zeros
+// of the sizes a chunker gives to runs of zeros have no file, they are always
+// considered present.
+#[derive(Clone, Debug)]
+pub struct ChunkStore {
+    root_path: PathBuf,
+    // Hash of all-zero chunk by chunk size
+    zero_hashes: Vec<(usize, HashSum)>,
+}
+impl ChunkStore {
+    // Store where every chunk has a file
+    pub fn new(root_path: &Path) -> Self {
+        Self {
+            root_path: root_path.to_path_buf(),
+            zero_hashes: Vec::new(),
+        }
+    }
+
+    // Store of the chunks of a dictionary, skipping its zero chunks
+    pub fn for_dictionary(root_path: &Path, dictionary: &storedict::StoreDictionary) -> Self {
+        match &dictionary.chunker_params {
+            Some(params) => Self::new(root_path).with_zero_chunks(params),
+            None => Self::new(root_path),
+        }
+    }
+
+    // Skip zero chunks of the sizes given by the chunker. Runs of zeros give
+    // chunks of either min or max chunk size (or the fixed size), only
+    // those are hashed rather than zeros of every chunk size.
+    pub fn with_zero_chunks(mut self, params: &storedict::ChunkerParameters) -> Self {
+        let hash_length = params.chunk_hash_length as usize;
+        for &size in &[params.min_chunk_size, params.max_chunk_size] {
+            let size = size as usize;
+            if size > 0 && !self.zero_hashes.iter().any(|(s, _)| *s == size) {
+                self.zero_hashes
+                    .push((size, HashSum::b2_digest(&vec![0; size], hash_length)));
+            }
+        }
+        self
+    }
+
+    pub fn is_zero_chunk(&self, hash: &HashSum, size: usize) -> bool {
+        self.zero_hashes
+            .iter()
+            .any(|(zero_size, zero_hash)| *zero_size == size && zero_hash == hash)
+    }
+
+    // Read a chunk from store and verify its hash.
+    pub async fn read_chunk(&self, hash: &HashSum, size: usize) -> Result<Vec<u8>, std::io::Error> {
+        if self.is_zero_chunk(hash, size) {
+            return Ok(vec![0; size]);
+        }
+        let chunk = tokio::fs::read(self.chunk_path(hash)).await?;
+        if HashSum::b2_digest(&chunk, hash.len()) != *hash {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("chunk {} is corrupt", hash),
+            ));
+        }
+        Ok(chunk)
+    }
+
+    pub fn chunk_path(&self, hash: &HashSum) -> PathBuf {
+        self.root_path.join(chunk_path_from_hash(hash))
+    }
+
+    // Test if a chunk is present in store. When verifying, a chunk which
+    // doesn't match its hash is treated as not present.
+    pub async fn chunk_present(&self, verify: bool, hash: &HashSum, size: usize) -> bool {
+        if self.is_zero_chunk(hash, size) {
+            return true;
+        }
+        match OpenOptions::new()
+            .read(true)
+            .open(self.chunk_path(hash))
+            .await
+        {
+            Ok(mut chunk_file) => {
+                if verify {
+                    let mut chunk_buf = Vec::with_capacity(size);
+                    if match chunk_file.read_to_end(&mut chunk_buf).await {
+                        Ok(_) => HashSum::b2_digest(&chunk_buf, hash.len()) != *hash,
+                        Err(_err) => false,
+                    } {
+                        // Chunk present but seems corrupt
+                        warn!("Chunk {} corrupt, will be re-written", hash);
+                        return false;
+                    }
+                }
+                true
+            }
+            // Chunk is not present
+            Err(_err) => false,
+        }
+    }
+
+    pub async fn filter_present_chunks(
+        &self,
+        verify: bool,
+        chunks: &ChunkIndex,
+    ) -> Result<ChunkIndex, std::io::Error> {
+        let mut new_index = ChunkIndex::new_empty();
+        for (hash, location) in chunks.iter_chunks() {
+            if !self.chunk_present(verify, hash, location.size()).await {
+                new_index.add_chunk(hash.clone(), location.size(), location.offsets());
+            }
+        }
+        Ok(new_index)
+    }
+
+    pub fn chunker_config_to_params(
+        conf: &bitar::chunker::Config,
+        chunk_hash_length: u32,
+    ) -> storedict::ChunkerParameters {
+        match conf {
+            bitar::chunker::Config::BuzHash(hash_config) => storedict::ChunkerParameters {
+                chunk_hash_length,
+                chunk_filter_bits: hash_config.filter_bits.bits(),
+                chunking_algorithm: storedict::chunker_parameters::ChunkingAlgorithm::Buzhash
+                    as i32,
+                min_chunk_size: hash_config.min_chunk_size as u32,
+                max_chunk_size: hash_config.max_chunk_size as u32,
+                rolling_hash_window_size: hash_config.window_size as u32,
+            },
+            bitar::chunker::Config::RollSum(hash_config) => storedict::ChunkerParameters {
+                chunk_hash_length,
+                chunk_filter_bits: hash_config.filter_bits.bits(),
+                chunking_algorithm: storedict::chunker_parameters::ChunkingAlgorithm::Rollsum
+                    as i32,
+                min_chunk_size: hash_config.min_chunk_size as u32,
+                max_chunk_size: hash_config.max_chunk_size as u32,
+                rolling_hash_window_size: hash_config.window_size as u32,
+            },
+            bitar::chunker::Config::FixedSize(fixed_size) => storedict::ChunkerParameters {
+                chunk_hash_length,
+                chunk_filter_bits: 0,
+                chunking_algorithm: storedict::chunker_parameters::ChunkingAlgorithm::FixedSize
+                    as i32,
+                min_chunk_size: 0,
+                max_chunk_size: *fixed_size as u32,
+                rolling_hash_window_size: 0,
+            },
+        }
+    }
+
+    pub fn dictionary(&self, archive: &bitar::Archive) -> storedict::StoreDictionary {
+        let mut chunk_to_index: HashMap<HashSum, usize> = HashMap::new();
+        let descriptors = archive
+            .chunk_descriptors()
+            .iter()
+            .enumerate()
+            .map(|(index, desc)| {
+                chunk_to_index.insert(desc.checksum.clone(), index);
+                storedict::ChunkDescriptor {
+                    checksum: desc.checksum.to_vec(),
+                    source_size: desc.source_size,
+                }
+            })
+            .collect();
+        storedict::StoreDictionary {
+            application_version: crate::PKG_VERSION.to_string(),
+            chunker_params: Some(Self::chunker_config_to_params(
+                archive.chunker_config(),
+                archive.chunk_hash_length() as u32,
+            )),
+            source_checksum: archive.source_checksum().to_vec(),
+            source_total_size: archive.total_source_size(),
+            source_order: archive
+                .iter_source_chunks()
+                .map(|(_, cd)| *chunk_to_index.get(&cd.checksum).unwrap() as u32)
+                .collect(),
+            chunk_descriptors: descriptors,
+            verity: None,
+            security_version: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl CloneOutput for ChunkStore {
+    type Error = std::io::Error;
+    async fn write_chunk(
+        &mut self,
+        hash: &HashSum,
+        _offsets: &[u64],
+        buf: &[u8],
+    ) -> Result<(), std::io::Error> {
+        if self.is_zero_chunk(hash, buf.len()) {
+            debug!("skip writing zero chunk {}", hash);
+            return Ok(());
+        }
+        let chunk_path = self.chunk_path(hash);
+        create_dir_all(chunk_path.parent().expect("chunk subdir")).await?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&chunk_path)
+            .await?;
+        debug!("write chunk {} to {}", hash, chunk_path.display());
+        file.write_all(buf).await.expect("write chunk file");
+        file.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use log::*;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Lock taken by everyone using the store. Shared for readers and writers of
+// new chunks, exclusive for operations removing or moving store content.
+const STORE_LOCK_FILE: &str = ".lock";
+// Lock serializing operations writing to the store.
+const WRITE_LOCK_FILE: &str = ".write.lock";
+// Lock serializing changes of slots and boot state.
+const SLOTS_LOCK_FILE: &str = ".slots.lock";
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    // Reading from store (eg mount)
+    Shared,
+    // Adding chunks and dictionaries to store (eg clone)
+    Write,
+    // Changing slots and boot state (eg switch or mount of current slot)
+    Slots,
+    // Removing or moving store content (eg gc, rm or migrate), with no one
+    // else using the store
+    Exclusive,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LockWait {
+    // Fail if the lock is held by someone else
+    No,
+    Timeout(Duration),
+    Forever,
+}
+
+// Advisory lock of a chunk store, released on drop.
+pub struct StoreLock {
+    _files: Vec<File>,
+}
+
+fn open_lock_file(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        // A read-only store can still be locked, if the lock file is there
+        .or_else(|_| File::open(path))
+}
+
+async fn lock_file(path: &Path, exclusive: bool, deadline: Option<Instant>) -> Option<File> {
+    let file = match open_lock_file(path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("failed to open lock file {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    let arg = if exclusive {
+        FlockArg::LockExclusiveNonblock
+    } else {
+        FlockArg::LockSharedNonblock
+    };
+    let mut logged = false;
+    loop {
+        match flock(file.as_raw_fd(), arg) {
+            Ok(()) => return Some(file),
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {}
+            Err(err) => {
+                error!("failed to lock {}: {}", path.display(), err);
+                return None;
+            }
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => return None,
+            _ => {}
+        }
+        if !logged {
+            info!("waiting for lock {}", path.display());
+            logged = true;
+        }
+        tokio::time::delay_for(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+impl StoreLock {
+    // Lock the store at the given root. Returns None if the lock could not be taken
+    // within the given wait time (or not at all).
+    pub async fn acquire(store_root: &Path, mode: LockMode, wait: LockWait) -> Option<Self> {
+        let deadline = match wait {
+            LockWait::No => Some(Instant::now()),
+            LockWait::Timeout(timeout) => Some(Instant::now() + timeout),
+            LockWait::Forever => None,
+        };
+        let mut files = Vec::new();
+        if mode == LockMode::Write || mode == LockMode::Exclusive {
+            files.push(lock_file(&store_root.join(WRITE_LOCK_FILE), true, deadline).await?);
+        }
+        if mode == LockMode::Slots {
+            files.push(lock_file(&store_root.join(SLOTS_LOCK_FILE), true, deadline).await?);
+        }
+        files.push(
+            lock_file(
+                &store_root.join(STORE_LOCK_FILE),
+                mode == LockMode::Exclusive,
+                deadline,
+            )
+            .await?,
+        );
+        debug!("locked store {} ({:?})", store_root.display(), mode);
+        Some(Self { _files: files })
+    }
+}
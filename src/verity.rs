@@ -0,0 +1,221 @@
+use bitar::HashSum;
+use log::*;
+use openssl::sha::Sha256;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+use crate::size_str::size_str;
+use crate::store::{read_dictionary, write_dictionary, ChunkStore};
+use crate::store_lock::{LockMode, LockWait, StoreLock};
+use crate::storedict;
+
+const HASH_ALGORITHM: &str = "sha256";
+const DIGEST_SIZE: usize = 32;
+const SUPERBLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub data_block_size: usize,
+    pub hash_block_size: usize,
+    // Random salt if None
+    pub salt: Option<Vec<u8>>,
+    // Record the root hash in the dictionary
+    pub store_root_hash: bool,
+    pub lock_wait: LockWait,
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn parse_hex(hex_str: &str) -> Option<Vec<u8>> {
+    if hex_str.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex_str.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex_str.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn salted_digest(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+    hasher.finish()
+}
+
+// Superblock as written by veritysetup (format version 1).
+fn superblock(opts: &Options, salt: &[u8], data_blocks: u64) -> Vec<u8> {
+    let mut uuid = [0; 16];
+    openssl::rand::rand_bytes(&mut uuid).expect("generate uuid");
+    // Random (version 4) uuid
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    let mut algorithm = [0; 32];
+    algorithm[..HASH_ALGORITHM.len()].copy_from_slice(HASH_ALGORITHM.as_bytes());
+    let mut salt_buf = [0; 256];
+    salt_buf[..salt.len()].copy_from_slice(salt);
+
+    let mut sb = Vec::with_capacity(SUPERBLOCK_SIZE);
+    sb.extend(b"verity\0\0");
+    // Format version and hash type (1 = normal)
+    sb.extend(&1u32.to_le_bytes());
+    sb.extend(&1u32.to_le_bytes());
+    sb.extend(&uuid);
+    sb.extend(&algorithm);
+    sb.extend(&(opts.data_block_size as u32).to_le_bytes());
+    sb.extend(&(opts.hash_block_size as u32).to_le_bytes());
+    sb.extend(&data_blocks.to_le_bytes());
+    sb.extend(&(salt.len() as u16).to_le_bytes());
+    sb.extend(&[0; 6]);
+    sb.extend(&salt_buf[..]);
+    sb.resize(SUPERBLOCK_SIZE, 0);
+    sb
+}
+
+// Pack the hashes of one level into zero padded hash blocks.
+fn hash_level(digests: impl Iterator<Item = [u8; DIGEST_SIZE]>, hash_block_size: usize) -> Vec<u8> {
+    let mut level = Vec::new();
+    for digest in digests {
+        level.extend(&digest);
+    }
+    level.resize(
+        (level.len() + hash_block_size - 1) / hash_block_size * hash_block_size,
+        0,
+    );
+    level
+}
+
+// Generate a dm-verity hash tree of the image of a dictionary. The tree is
+// written with a superblock, as veritysetup does by default.
+pub async fn verity(dictionary_path: &Path, hash_tree_path: &Path, opts: &Options) {
+    let store_root = dictionary_path.parent().expect("store root");
+    let mode = if opts.store_root_hash {
+        LockMode::Write
+    } else {
+        LockMode::Shared
+    };
+    let _lock = StoreLock::acquire(store_root, mode, opts.lock_wait)
+        .await
+        .unwrap_or_else(|| panic!("store {} is locked", store_root.display()));
+    let mut dictionary = read_dictionary(dictionary_path).await;
+    let store = ChunkStore::for_dictionary(store_root, &dictionary);
+    let salt = match &opts.salt {
+        Some(salt) => salt.clone(),
+        None => {
+            let mut salt = vec![0; 32];
+            openssl::rand::rand_bytes(&mut salt).expect("generate salt");
+            salt
+        }
+    };
+    let data_blocks = dictionary.source_total_size / opts.data_block_size as u64;
+    if dictionary.source_total_size % opts.data_block_size as u64 != 0 {
+        warn!(
+            "image size is not a multiple of the data block size, last {} bytes are not covered",
+            dictionary.source_total_size % opts.data_block_size as u64
+        );
+    }
+    if data_blocks == 0 {
+        panic!("image is smaller than a data block");
+    }
+    info!(
+        "generating hash tree of {} ({} data blocks) to {}",
+        dictionary_path.display(),
+        data_blocks,
+        hash_tree_path.display()
+    );
+
+    // Hash the data blocks of the image
+    let mut data_digests: Vec<[u8; DIGEST_SIZE]> = Vec::with_capacity(data_blocks as usize);
+    let mut block: Vec<u8> = Vec::with_capacity(opts.data_block_size);
+    for index in &dictionary.source_order {
+        if data_digests.len() as u64 == data_blocks {
+            break;
+        }
+        let cd = &dictionary.chunk_descriptors[*index as usize];
+        let hash = HashSum::from_slice(&cd.checksum[..]);
+        let chunk = store
+            .read_chunk(&hash, cd.source_size as usize)
+            .await
+            .unwrap_or_else(|err| panic!("failed to read chunk {}: {}", hash, err));
+        let mut chunk = &chunk[..];
+        while !chunk.is_empty() && (data_digests.len() as u64) < data_blocks {
+            let take = std::cmp::min(opts.data_block_size - block.len(), chunk.len());
+            block.extend(&chunk[..take]);
+            chunk = &chunk[take..];
+            if block.len() == opts.data_block_size {
+                data_digests.push(salted_digest(&salt, &block));
+                block.clear();
+            }
+        }
+    }
+
+    // Build levels from the data block hashes up until a level fits in a
+    // single hash block. The root hash is the hash of that block. A single
+    // data block has no levels, its hash is the root hash.
+    let mut levels: Vec<Vec<u8>> = Vec::new();
+    let root_hash = if data_digests.len() == 1 {
+        data_digests[0]
+    } else {
+        levels.push(hash_level(data_digests.into_iter(), opts.hash_block_size));
+        while levels.last().unwrap().len() > opts.hash_block_size {
+            let below = levels.last().unwrap();
+            let level = hash_level(
+                below
+                    .chunks(opts.hash_block_size)
+                    .map(|block| salted_digest(&salt, block)),
+                opts.hash_block_size,
+            );
+            levels.push(level);
+        }
+        salted_digest(&salt, levels.last().unwrap())
+    };
+
+    // Superblock padded to a hash block, then the levels with the top level first
+    let mut hash_tree = tokio::fs::File::create(hash_tree_path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to create {}: {}", hash_tree_path.display(), err));
+    let mut sb = superblock(opts, &salt, data_blocks);
+    sb.resize(opts.hash_block_size, 0);
+    hash_tree.write_all(&sb).await.expect("write hash tree");
+    let mut tree_size = sb.len() as u64;
+    for level in levels.iter().rev() {
+        hash_tree.write_all(level).await.expect("write hash tree");
+        tree_size += level.len() as u64;
+    }
+    hash_tree.flush().await.expect("flush hash tree");
+
+    if opts.store_root_hash {
+        let hash_tree_name = if hash_tree_path.parent() == Some(store_root) {
+            hash_tree_path
+                .file_name()
+                .expect("hash tree file name")
+                .to_string_lossy()
+                .to_string()
+        } else {
+            std::fs::canonicalize(hash_tree_path)
+                .expect("hash tree path")
+                .display()
+                .to_string()
+        };
+        dictionary.verity = Some(storedict::VerityParameters {
+            root_hash: root_hash.to_vec(),
+            salt: salt.clone(),
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+            data_block_size: opts.data_block_size as u32,
+            hash_block_size: opts.hash_block_size as u32,
+            hash_tree: hash_tree_name,
+        });
+        write_dictionary(dictionary_path, &dictionary).await;
+        info!("stored root hash in {}", dictionary_path.display());
+    }
+    info!(
+        "wrote hash tree of {} levels ({}) to {}",
+        levels.len(),
+        size_str(tree_size),
+        hash_tree_path.display()
+    );
+    println!("Root hash: {}", hex(&root_hash));
+    println!("Salt: {}", hex(&salt));
+}
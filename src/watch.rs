@@ -0,0 +1,296 @@
+use log::*;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::channel::parse_pointer;
+use crate::clone::{self, InputArchive};
+use crate::progress::Progress;
+use crate::store::{checksum_name, find_dictionary, read_dictionary};
+use crate::store_lock::{LockMode, StoreLock};
+
+// Name of the dictionary while being cloned, published when complete.
+// Unique per process, to not touch the staged release of another watcher
+// of the same store.
+fn staged_name() -> String {
+    format!(".watch.{}.part", std::process::id())
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub interval: Duration,
+    // Poll at a random time within interval +/- jitter
+    pub jitter: Duration,
+    // Location is a channel pointing at the current release archive
+    pub channel: bool,
+    // Command run (by sh) when a new release is in store
+    pub hook: Option<String>,
+    pub clone: clone::Options,
+}
+
+// Identifies the content at location, to only read it again when changed.
+// Servers not giving an ETag may still give the modification time.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Tag {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+// Fetch location unless unchanged since the given tag. Returns the new tag
+// and the content, which is only read if asked for.
+async fn poll(
+    location: &str,
+    client: &reqwest::Client,
+    tag: &Tag,
+    read_body: bool,
+) -> Result<Option<(Tag, Vec<u8>)>, String> {
+    match location.parse::<url::Url>() {
+        Ok(url) => {
+            let mut request = if read_body {
+                client.get(url)
+            } else {
+                client.head(url)
+            };
+            if let Some(etag) = &tag.etag {
+                request = request.header(IF_NONE_MATCH, etag.as_str());
+            } else if let Some(last_modified) = &tag.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+            let response = request.send().await.map_err(|err| err.to_string())?;
+            if response.status() == StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            let response = response.error_for_status().map_err(|err| err.to_string())?;
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from)
+            };
+            let new_tag = Tag {
+                etag: header(ETAG),
+                last_modified: header(LAST_MODIFIED),
+            };
+            let body = if read_body {
+                response
+                    .bytes()
+                    .await
+                    .map_err(|err| err.to_string())?
+                    .to_vec()
+            } else {
+                Vec::new()
+            };
+            Ok(Some((new_tag, body)))
+        }
+        Err(_) => {
+            let modified = std::fs::metadata(location)
+                .and_then(|meta| meta.modified())
+                .map_err(|err| format!("{}: {}", location, err))?;
+            let new_tag = Tag {
+                etag: None,
+                last_modified: Some(format!(
+                    "{:?}",
+                    modified.duration_since(UNIX_EPOCH).unwrap_or_default()
+                )),
+            };
+            if new_tag == *tag {
+                return Ok(None);
+            }
+            let body = if read_body {
+                std::fs::read(location).map_err(|err| format!("{}: {}", location, err))?
+            } else {
+                Vec::new()
+            };
+            Ok(Some((new_tag, body)))
+        }
+    }
+}
+
+// Dictionary name of a release from its archive file name.
+fn release_name(location: &str) -> String {
+    let file_name = location.trim_end_matches('/').rsplit('/').next().unwrap();
+    file_name.trim_end_matches(".cba").to_string()
+}
+
+async fn run_hook(hook: &str, store_root: &Path, release: &Path, version: Option<&str>) {
+    let mut command = std::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(hook)
+        .env("IHOP_STORE", store_root)
+        .env("IHOP_RELEASE", release);
+    if let Some(version) = version {
+        command.env("IHOP_VERSION", version);
+    }
+    info!("running hook {:?}", hook);
+    match tokio::task::spawn_blocking(move || command.status()).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => warn!("hook {:?} failed ({})", hook, status),
+        Ok(Err(err)) => warn!("failed to run hook {:?}: {}", hook, err),
+        Err(err) => warn!("failed to run hook {:?}: {}", hook, err),
+    }
+}
+
+// Check location for a new release and clone it. The tag is only updated
+// when the release has been handled, so a failed clone is retried.
+async fn check<F>(
+    location: &str,
+    store_root: &Path,
+    client: &reqwest::Client,
+    tag: &mut Tag,
+    opts: &Options,
+    archive_input: &F,
+    progress: &Progress,
+) where
+    F: Fn(&str) -> InputArchive,
+{
+    let (new_tag, body) = match poll(location, client, tag, opts.channel).await {
+        Ok(Some(changed)) => changed,
+        Ok(None) => {
+            debug!("{} is unchanged", location);
+            return;
+        }
+        Err(err) => {
+            warn!("failed to poll {}: {}", location, err);
+            return;
+        }
+    };
+    let mut clone_opts = opts.clone.clone();
+    let (input, name, version) = if opts.channel {
+        let pointer = match parse_pointer(&body, location) {
+            Ok(pointer) => pointer,
+            Err(err) => {
+                error!("{}: {}", location, err);
+                return;
+            }
+        };
+        if let Some(path) = find_dictionary(store_root, pointer.source_checksum.slice()) {
+            info!(
+                "release {} already in store as {}",
+                pointer.version,
+                path.display()
+            );
+            *tag = new_tag;
+            return;
+        }
+        info!("new release {} at {}", pointer.version, pointer.url);
+        clone_opts.source_checksum = Some(pointer.source_checksum);
+        if let Some(security_version) = pointer.security_version {
+            clone_opts.security_version = security_version;
+        }
+        (
+            archive_input(&pointer.url),
+            release_name(&pointer.url),
+            Some(pointer.version),
+        )
+    } else {
+        info!("{} has changed", location);
+        // Servers may not tell if the archive changed, so read its header
+        // before cloning a release which might already be in store.
+        let input = archive_input(location);
+        let source_checksum = match clone::source_checksum_of(&input).await {
+            Ok(source_checksum) => source_checksum,
+            Err(err) => {
+                error!("{}", err);
+                return;
+            }
+        };
+        if let Some(path) = find_dictionary(store_root, source_checksum.slice()) {
+            info!("release already in store as {}", path.display());
+            *tag = new_tag;
+            return;
+        }
+        clone_opts.source_checksum = Some(source_checksum);
+        (input, release_name(location), None)
+    };
+
+    // Clone to a staged dictionary first, as the release of an archive
+    // isn't known until its header has been read.
+    let staged = store_root.join(staged_name());
+    let _ = std::fs::remove_file(&staged);
+    clone_opts.force_create = true;
+    if let Err(err) = clone::clone(input, &staged, store_root, &clone_opts, progress).await {
+        error!("{}", err);
+        let _ = std::fs::remove_file(&staged);
+        return;
+    }
+    let source_checksum = read_dictionary(&staged).await.source_checksum;
+    let release = {
+        let _lock =
+            match StoreLock::acquire(store_root, LockMode::Write, clone_opts.lock_wait).await {
+                Some(lock) => lock,
+                None => {
+                    error!("store {} is locked", store_root.display());
+                    let _ = std::fs::remove_file(&staged);
+                    return;
+                }
+            };
+        if let Some(path) = find_dictionary(store_root, &source_checksum) {
+            info!("release already in store as {}", path.display());
+            let _ = std::fs::remove_file(&staged);
+            *tag = new_tag;
+            return;
+        }
+        let mut release = store_root.join(&name);
+        if release.exists() {
+            // Name taken by another release, tell them apart by checksum
+            release = store_root.join(checksum_name(&name, &source_checksum));
+        }
+        if let Err(err) = std::fs::rename(&staged, &release) {
+            error!("failed to publish {}: {}", release.display(), err);
+            let _ = std::fs::remove_file(&staged);
+            return;
+        }
+        release
+    };
+    info!("new release in store as {}", release.display());
+    *tag = new_tag;
+    if let Some(hook) = &opts.hook {
+        run_hook(hook, store_root, &release, version.as_deref()).await;
+    }
+}
+
+fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    let mut random = [0; 8];
+    openssl::rand::rand_bytes(&mut random).expect("random jitter");
+    let span = jitter.as_millis() as u64 * 2 + 1;
+    let offset = u64::from_le_bytes(random) % span;
+    let millis = (interval.as_millis() as u64 + offset).saturating_sub(jitter.as_millis() as u64);
+    Duration::from_millis(std::cmp::max(millis, 1000))
+}
+
+// Poll a channel or archive for new releases and clone them to the store.
+pub async fn watch<F>(
+    location: &str,
+    store_root: &Path,
+    client: reqwest::Client,
+    opts: &Options,
+    archive_input: F,
+    progress: &Progress,
+) where
+    F: Fn(&str) -> InputArchive,
+{
+    info!(
+        "watching {} every {:?} (+/- {:?}) for new releases to {}",
+        location,
+        opts.interval,
+        opts.jitter,
+        store_root.display()
+    );
+    let mut tag = Tag::default();
+    loop {
+        check(
+            location,
+            store_root,
+            &client,
+            &mut tag,
+            opts,
+            &archive_input,
+            progress,
+        )
+        .await;
+        tokio::time::delay_for(jittered(opts.interval, opts.jitter)).await;
+    }
+}